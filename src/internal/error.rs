@@ -1,6 +1,7 @@
 use ssh2;
 use std::fmt;
 use std::io;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum ReployError {
@@ -9,8 +10,14 @@ pub enum ReployError {
     Runtime(String),
     AuthFailed,
     ConnectionFailed,
+    HostKeyVerificationFailed(String),
     CommandFailed(i32, String),
+    /// Timeout, command and the recipe line it was run from, once known
+    CommandTimedOut(Duration, String, Option<usize>),
+    BecomeFailed(String),
     InvalidRecipe(String),
+    InvalidInventory(String),
+    CallDepthExceeded(usize, Vec<String>),
     WithContext {
         source: Box<ReployError>,
         context: String,
@@ -35,10 +42,27 @@ impl fmt::Display for ReployError {
             ReployError::Runtime(s) => write!(f, "Runtime error: {}", s),
             ReployError::AuthFailed => write!(f, "Authentication failed"),
             ReployError::ConnectionFailed => write!(f, "Connection failed"),
+            ReployError::HostKeyVerificationFailed(s) => {
+                write!(f, "Host key verification failed: {}", s)
+            }
             ReployError::CommandFailed(code, msg) => {
                 write!(f, "Command failed with exit code {}: {}", code, msg)
             }
+            ReployError::CommandTimedOut(timeout, command, line) => {
+                if let Some(line) = line {
+                    write!(f, "Line {}: ", line)?;
+                }
+                write!(f, "Command timed out after {:?}: {}", timeout, command)
+            }
+            ReployError::BecomeFailed(s) => write!(f, "Privilege escalation failed: {}", s),
             ReployError::InvalidRecipe(s) => write!(f, "Invalid recipe: {}", s),
+            ReployError::InvalidInventory(s) => write!(f, "Invalid inventory: {}", s),
+            ReployError::CallDepthExceeded(depth, stack) => write!(
+                f,
+                "Maximum call depth of {} exceeded\nCall stack: {}",
+                depth,
+                stack.join(" -> ")
+            ),
             ReployError::WithContext { source, context } => {
                 write!(f, "{}\nContext: {}", source, context)
             }
@@ -1,24 +1,55 @@
+use std::collections::HashMap;
 use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use dialoguer::{Input, Password, theme::ColorfulTheme};
 use regex::Regex;
 
 use super::error::ReployError;
-use super::executor::Executor;
+use super::executor::{Executor, LocalExecutor, RunOptions, StdinSource};
 use super::token::{Token, Type};
-use super::{Recipe, Statement, util};
+use super::{LOCAL_TARGET, Recipe, Statement, Stdio, shell, util};
 
 const TARGET_KEY: &str = "$TARGET_KEY";
 
 const STDOUT: &str = "stdout";
 const STDERR: &str = "stderr";
+const STDOUT_RAW: &str = "stdout_raw";
+const STDERR_RAW: &str = "stderr_raw";
+const STDOUT_BASE64: &str = "stdout_base64";
+const STDERR_BASE64: &str = "stderr_base64";
+const STDOUT_FILE: &str = "stdout_file";
+const STDERR_FILE: &str = "stderr_file";
 const EXIT_CODE: &str = "exit_code";
 
+const DEFAULT_MAX_CALL_DEPTH: usize = 64;
+
+/// User a `Become` block without one runs its commands as
+const DEFAULT_BECOME_USER: &str = "root";
+
+/// Working directory, environment and interpreter set by `Cd`, `Env` and
+/// `Shell` for one target
+#[derive(Default)]
+struct Environment {
+    cwd: Option<String>,
+    env: Vec<(String, String)>,
+    shell: Option<String>,
+}
+
 pub struct Evaluator {
     recipe: Recipe,
     is_end: bool,
     is_verbose: bool,
     executor: Box<dyn Executor>,
+    local: LocalExecutor,
+    is_local: bool,
+    target: Option<(String, Option<String>)>,
+    call_stack: Vec<String>,
+    max_call_depth: usize,
+    command_timeout: Option<Duration>,
+    become_user: Option<String>,
+    environments: HashMap<String, Environment>,
 }
 
 impl Evaluator {
@@ -28,12 +59,59 @@ impl Evaluator {
             is_end: false,
             is_verbose: verbose,
             executor,
+            local: LocalExecutor::new(),
+            is_local: false,
+            target: None,
+            call_stack: Vec::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            command_timeout: None,
+            become_user: None,
+            environments: HashMap::new(),
         }
     }
 
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Kill commands that run longer than this unless they set their own `Timeout`
+    pub fn set_command_timeout(&mut self, timeout: Duration) {
+        self.command_timeout = Some(timeout).filter(|t| !t.is_zero());
+    }
+
+    /// Password for sudo when `On local` blocks run commands as another user
+    pub fn set_become_password(&mut self, password: &str) {
+        self.local.set_become_password(password);
+    }
+
+    /// Whether the recipe was stopped by an `End` statement
+    pub fn is_end(&self) -> bool {
+        self.is_end
+    }
+
     pub fn run(&mut self) -> Result<(), ReployError> {
-        self.resolve_statement(self.recipe.task.to_vec())?;
-        self.executor.disconnect()
+        let result = self.resolve_statement(self.recipe.task.to_vec());
+        // Close every pooled connection even when the recipe failed
+        let closed = self.executor.disconnect();
+        result.and(closed)
+    }
+
+    /// The executor statements currently run against, which is the local
+    /// machine inside an `On local` block
+    fn executor(&mut self) -> &mut dyn Executor {
+        if self.is_local {
+            &mut self.local
+        } else {
+            self.executor.as_mut()
+        }
+    }
+
+    fn stdio(&self) -> &Stdio {
+        if self.is_local {
+            self.local.stdio()
+        } else {
+            self.executor.stdio()
+        }
     }
 
     fn resolve_statement(&mut self, statements: Vec<Statement>) -> Result<(), ReployError> {
@@ -67,11 +145,16 @@ impl Evaluator {
                     }
                     self.resolve_list_loop(variable, list, body)?;
                 }
-                Statement::While { condition, body } => {
+                Statement::While {
+                    condition,
+                    max,
+                    timeout,
+                    body,
+                } => {
                     if self.is_verbose {
                         println!("Executing WHILE statement");
                     }
-                    self.resolve_while(condition, body)?;
+                    self.resolve_while(condition, max, timeout, body)?;
                 }
                 Statement::When {
                     condition,
@@ -82,6 +165,18 @@ impl Evaluator {
                     }
                     self.resolve_when(condition, branches)?;
                 }
+                Statement::On { target, via, body } => {
+                    if self.is_verbose {
+                        println!("Executing ON statement");
+                    }
+                    self.resolve_on(target, via, body)?;
+                }
+                Statement::Become { user, body } => {
+                    if self.is_verbose {
+                        println!("Executing BECOME statement");
+                    }
+                    self.resolve_become(user, body)?;
+                }
                 Statement::Simple { token, arguments } => {
                     let line_num = token.line_num;
                     if self.is_verbose {
@@ -102,6 +197,12 @@ impl Evaluator {
                         Type::CALL => self.resolve_call(arguments),
                         Type::WAIT => self.resolve_wait(arguments),
                         Type::SLEEP => self.resolve_sleep(arguments),
+                        Type::CD => self.resolve_cd(arguments),
+                        Type::INTERACT => self.resolve_interact(arguments),
+                        Type::ENV => self.resolve_env(arguments),
+                        Type::SHELL => self.resolve_shell(arguments),
+                        // Already says where it failed, so it is not wrapped like the errors below
+                        Type::FAIL => return self.resolve_fail(line_num, arguments),
                         Type::SET => {
                             let k = arguments[0].literal.clone();
                             let v = util::evaluate_expression(
@@ -125,7 +226,16 @@ impl Evaluator {
                             Ok(())
                         }
                     };
-                    result.map_err(|e| ReployError::Runtime(format!("Line {}: {}", line_num, e)))?
+                    result.map_err(|e| match e {
+                        // Already carries the full call stack
+                        ReployError::CallDepthExceeded(..) => e,
+                        // Kept distinct so that callers can tell a hung command apart
+                        ReployError::CommandTimedOut(timeout, command, None) => {
+                            ReployError::CommandTimedOut(timeout, command, Some(line_num))
+                        }
+                        ReployError::CommandTimedOut(..) => e,
+                        _ => ReployError::Runtime(format!("Line {}: {}", line_num, e)),
+                    })?
                 }
             }
         }
@@ -137,41 +247,167 @@ impl Evaluator {
         if self.is_verbose {
             println!("run command: {}", cmd);
         }
-        self.executor.execute(&cmd)?;
+        // Output is echoed as it arrives unless the statement asks for quiet
+        let target = self.target_name();
+        let environment = self.environments.get(&target);
+        // A target's `Shell` wins over the recipe's, and an empty one restores the default
+        let shell = match environment
+            .and_then(|e| e.shell.clone())
+            .or_else(|| self.recipe.shell.clone())
+        {
+            Some(spec) => Some(self.replace_variable(spec)?).filter(|s| !s.trim().is_empty()),
+            None => None,
+        };
+        let mut options = RunOptions {
+            cwd: environment.and_then(|e| e.cwd.clone()),
+            env: environment.map(|e| e.env.clone()).unwrap_or_default(),
+            echo: Some(target),
+            timeout: self.command_timeout,
+            become_user: self.become_user.clone(),
+            pty: false,
+            stdin: None,
+            limit: None,
+            save_stdout: None,
+            shell: shell.as_deref().map(shell::interpreter).transpose()?,
+        };
+        let mut rest = arguments[1..].iter();
+        while let Some(option) = rest.next() {
+            match option.token_type {
+                Type::QUIET => options.echo = None,
+                Type::PTY => options.pty = true,
+                Type::STDIN => {
+                    let data = rest.next().unwrap();
+                    options.stdin = Some(StdinSource::Data(
+                        self.replace_variable(data.literal.clone())?,
+                    ));
+                }
+                Type::LIMIT => {
+                    let value = rest.next().unwrap();
+                    let limit = util::parse_size(&self.replace_variable(value.literal.clone())?)?;
+                    options.limit = Some(limit);
+                }
+                Type::SAVESTDOUT => {
+                    let path = rest.next().unwrap();
+                    let path = self.replace_variable(path.literal.clone())?;
+                    options.save_stdout = Some(PathBuf::from(path));
+                }
+                Type::STDINFILE => {
+                    let path = rest.next().unwrap();
+                    let path = self.replace_variable(path.literal.clone())?;
+                    options.stdin = Some(StdinSource::File(PathBuf::from(path)));
+                }
+                Type::TIMEOUT => {
+                    let value = rest.next().unwrap();
+                    let timeout =
+                        util::parse_duration(&self.replace_variable(value.literal.clone())?)?;
+                    // `Timeout 0` lifts the default for a single command
+                    options.timeout = Some(timeout).filter(|t| !t.is_zero());
+                }
+                Type::AS => {
+                    let user = rest.next().unwrap();
+                    options.become_user = Some(self.replace_variable(user.literal.clone())?);
+                }
+                _ => {}
+            }
+        }
+        self.executor().execute(&cmd, &options)?;
         Ok(())
     }
 
-    fn resolve_let(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
-        match arguments[2].literal.as_str() {
-            EXIT_CODE => {
-                self.recipe.variables.insert(
-                    arguments[0].literal.clone(),
-                    self.executor.stdio().exit_code.to_string(),
-                );
-            }
-            STDOUT => {
-                self.recipe.variables.insert(
-                    arguments[0].literal.clone(),
-                    self.executor.stdio().stdout.clone(),
-                );
-            }
-            STDERR => {
-                self.recipe.variables.insert(
-                    arguments[0].literal.clone(),
-                    self.executor.stdio().stderr.clone(),
-                );
+    /// Name of the target commands currently run on, which keys its `Cd` and `Env`
+    fn target_name(&self) -> String {
+        self.recipe
+            .variables
+            .get(TARGET_KEY)
+            .cloned()
+            .unwrap_or_else(|| LOCAL_TARGET.to_string())
+    }
+
+    fn resolve_interact(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
+        let command = match arguments.first() {
+            Some(command) => Some(self.replace_variable(command.literal.clone())?),
+            None => None,
+        };
+        self.executor().interact(command.as_deref())
+    }
+
+    fn resolve_cd(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
+        let path = self.replace_variable(arguments[0].literal.clone())?;
+        let environment = self.environments.entry(self.target_name()).or_default();
+        // A relative path is taken from the previous `Cd`, like the shell would
+        let cwd = match &environment.cwd {
+            Some(cwd) if !path.starts_with('/') && !path.starts_with('~') => {
+                format!("{}/{}", cwd.trim_end_matches('/'), path)
             }
+            _ => path,
+        };
+        if self.is_verbose {
+            println!("Changing directory to {}", cwd);
+        }
+        environment.cwd = Some(cwd);
+        Ok(())
+    }
+
+    fn resolve_shell(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
+        let spec = self.replace_variable(arguments[0].literal.clone())?;
+        if !spec.trim().is_empty() {
+            shell::interpreter(&spec)?;
+        }
+        if self.is_verbose {
+            println!("Running commands with {}", spec);
+        }
+        let environment = self.environments.entry(self.target_name()).or_default();
+        environment.shell = Some(spec);
+        Ok(())
+    }
+
+    fn resolve_env(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
+        let name = arguments[0].literal.clone();
+        let value = self.replace_variable(arguments[1].literal.clone())?;
+        if self.is_verbose {
+            println!("Setting environment variable {} to {}", name, value);
+        }
+        let environment = self.environments.entry(self.target_name()).or_default();
+        environment.env.retain(|(k, _)| *k != name);
+        environment.env.push((name, value));
+        Ok(())
+    }
+
+    fn resolve_let(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
+        let stdio = self.stdio();
+        // Output that is not valid UTF-8 survives intact only in the base64 views
+        let text = |data: &[u8]| String::from_utf8_lossy(data).to_string();
+        let file = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default()
+        };
+        let value = match arguments[2].literal.as_str() {
+            EXIT_CODE => stdio.exit_code.to_string(),
+            STDOUT => text(&stdio.stdout).trim().to_string(),
+            STDERR => text(&stdio.stderr).trim().to_string(),
+            STDOUT_RAW => text(&stdio.stdout),
+            STDERR_RAW => text(&stdio.stderr),
+            STDOUT_BASE64 => util::base64_encode_padded(&stdio.stdout),
+            STDERR_BASE64 => util::base64_encode_padded(&stdio.stderr),
+            // Where output past the capture limit went, empty when it all fit
+            STDOUT_FILE => file(&stdio.stdout_file),
+            STDERR_FILE => file(&stdio.stderr_file),
             _ => {
                 return Err(ReployError::Runtime(format!(
                     "Invalid LET operation: {}",
                     arguments[2].literal
                 )));
             }
-        }
+        };
+        self.recipe
+            .variables
+            .insert(arguments[0].literal.clone(), value);
         Ok(())
     }
 
     fn resolve_ask(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
+        let _prompt = util::prompt_lock();
         let input = Input::with_theme(&ColorfulTheme::default())
             .with_prompt(arguments[0].literal.clone())
             .interact_text()
@@ -188,6 +424,7 @@ impl Evaluator {
     }
 
     fn resolve_password(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
+        let _prompt = util::prompt_lock();
         let password = Password::with_theme(&ColorfulTheme::default())
             .with_prompt(arguments[0].literal.clone())
             .interact()
@@ -203,7 +440,7 @@ impl Evaluator {
         Ok(())
     }
 
-    fn resolve_snd(&self, arguments: Vec<Token>) -> Result<(), ReployError> {
+    fn resolve_snd(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
         let source = self.replace_variable(arguments[0].literal.clone())?;
         let dest = self.replace_variable(arguments[1].literal.clone())?;
 
@@ -211,10 +448,10 @@ impl Evaluator {
             println!("Sending: '{}' -> '{}'", source, dest);
         }
 
-        self.executor.send(&source, &dest)
+        self.executor().send(&source, &dest)
     }
 
-    fn resolve_rcv(&self, arguments: Vec<Token>) -> Result<(), ReployError> {
+    fn resolve_rcv(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
         let source = self.replace_variable(arguments[0].literal.clone())?;
         let dest = self.replace_variable(arguments[1].literal.clone())?;
 
@@ -222,7 +459,7 @@ impl Evaluator {
             println!("Receiving: '{}' -> '{}'", source, dest);
         }
 
-        self.executor.recv(&source, &dest)
+        self.executor().recv(&source, &dest)
     }
 
     fn resolve_call(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
@@ -234,8 +471,19 @@ impl Evaluator {
             .recipe
             .labels
             .get(label.as_str())
-            .ok_or_else(|| ReployError::Runtime(format!("Label {} not found", label)))?;
-        self.resolve_statement(label_statements.to_vec())
+            .ok_or_else(|| ReployError::Runtime(format!("Label {} not found", label)))?
+            .to_vec();
+
+        if self.call_stack.len() >= self.max_call_depth {
+            let mut stack = self.call_stack.clone();
+            stack.push(label);
+            return Err(ReployError::CallDepthExceeded(self.max_call_depth, stack));
+        }
+
+        self.call_stack.push(label);
+        let result = self.resolve_statement(label_statements);
+        self.call_stack.pop();
+        result
     }
 
     fn resolve_print(&self, arguments: Vec<Token>) -> Result<(), ReployError> {
@@ -249,6 +497,15 @@ impl Evaluator {
         Ok(())
     }
 
+    /// Stop the recipe with an error, unlike `End` which stops it successfully
+    fn resolve_fail(&self, line_num: usize, arguments: Vec<Token>) -> Result<(), ReployError> {
+        let message = self.replace_variable(arguments[0].literal.clone())?;
+        Err(ReployError::Runtime(format!(
+            "Line {}: {}",
+            line_num, message
+        )))
+    }
+
     fn replace_variable(&self, mut s: String) -> Result<String, ReployError> {
         if self.is_verbose {
             println!("Replacing variables in: {}", s);
@@ -280,18 +537,18 @@ impl Evaluator {
     ) -> Result<(), ReployError> {
         let start_val = start
             .literal
-            .parse::<i32>()
+            .parse::<i64>()
             .map_err(|_| ReployError::Runtime(format!("Invalid start value: {}", start.literal)))?;
 
         let end_val = end
             .literal
-            .parse::<i32>()
+            .parse::<i64>()
             .map_err(|_| ReployError::Runtime(format!("Invalid end value: {}", end.literal)))?;
 
         let step_val = match step {
             Some(t) => t
                 .literal
-                .parse::<i32>()
+                .parse::<i64>()
                 .map_err(|_| ReployError::Runtime(format!("Invalid step value: {}", t.literal)))?,
             None => 1,
         };
@@ -304,7 +561,10 @@ impl Evaluator {
                 .variables
                 .insert(variable.literal.clone(), current.to_string());
             self.resolve_statement(body.clone())?;
-            current += step_val;
+            current = match current.checked_add(step_val) {
+                Some(next) => next,
+                None => break,
+            };
         }
 
         if let Some(val) = original_value {
@@ -317,28 +577,26 @@ impl Evaluator {
     }
 
     fn resolve_sleep(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
-        let seconds = arguments[0].literal.parse::<u64>().map_err(|_| {
-            ReployError::Runtime(format!("Invalid sleep duration: {}", arguments[0].literal))
-        })?;
+        let duration = util::parse_duration(&self.replace_variable(arguments[0].literal.clone())?)?;
 
         if self.is_verbose {
-            println!("Sleeping for {} seconds", seconds);
+            println!("Sleeping for {:?}", duration);
         }
 
-        std::thread::sleep(std::time::Duration::from_secs(seconds));
+        std::thread::sleep(duration);
         Ok(())
     }
 
     fn resolve_wait(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
         let mode = self.replace_variable(arguments[0].literal.clone())?;
         let target = self.replace_variable(arguments[1].literal.clone())?;
-        let timeout = arguments[2].literal.parse::<u64>().unwrap_or(30);
+        let timeout = util::parse_duration(&self.replace_variable(arguments[2].literal.clone())?)?;
 
         let start = std::time::Instant::now();
 
         match mode.as_str() {
             "port_open" => {
-                while start.elapsed().as_secs() < timeout {
+                while start.elapsed() < timeout {
                     if std::net::TcpStream::connect(target.clone()).is_ok() {
                         return Ok(());
                     }
@@ -350,7 +608,7 @@ impl Evaluator {
                 )))
             }
             "file_exists" => {
-                while start.elapsed().as_secs() < timeout {
+                while start.elapsed() < timeout {
                     if std::path::Path::new(&target).exists() {
                         return Ok(());
                     }
@@ -369,14 +627,92 @@ impl Evaluator {
     }
 
     fn resolve_target(&mut self, arguments: Vec<Token>) -> Result<(), ReployError> {
-        let target = &arguments[0].literal;
+        let target = self.replace_variable(arguments[0].literal.clone())?;
         self.recipe
             .variables
             .insert(TARGET_KEY.to_string(), target.clone());
-        self.executor.connect(target)?;
+        let via = match arguments.get(1) {
+            Some(jump) => Some(self.replace_variable(jump.literal.clone())?),
+            None => None,
+        };
+        self.executor.connect(&target, via.as_deref())?;
+        self.target = Some((target, via));
         Ok(())
     }
 
+    fn resolve_on(
+        &mut self,
+        target: Token,
+        via: Option<Token>,
+        body: Vec<Statement>,
+    ) -> Result<(), ReployError> {
+        let line_num = target.line_num;
+        let target = self.replace_variable(target.literal)?;
+        let via = match via {
+            Some(jump) => Some(self.replace_variable(jump.literal)?),
+            None => None,
+        };
+
+        let previous_local = self.is_local;
+        let previous_target = self.target.clone();
+        let previous_key = self.recipe.variables.get(TARGET_KEY).cloned();
+        if target == LOCAL_TARGET {
+            self.is_local = true;
+        } else {
+            // A local executor would silently run the block here instead
+            if !self.executor.is_remote() {
+                return Err(ReployError::Runtime(format!(
+                    "Line {}: On {} needs a remote target, but this recipe runs on the local \
+                     machine; use `reploy ssh` or `On local`",
+                    line_num, target
+                )));
+            }
+            self.executor.connect(&target, via.as_deref())?;
+            self.is_local = false;
+            self.target = Some((target.clone(), via));
+        }
+        self.recipe
+            .variables
+            .insert(TARGET_KEY.to_string(), target.clone());
+
+        let result = self.resolve_statement(body);
+
+        // Switch back even when the block failed; pooled sessions make this cheap
+        let restored = match &previous_target {
+            Some((previous, via)) if self.target != previous_target => {
+                self.executor.connect(previous, via.as_deref())
+            }
+            Some(_) => Ok(()),
+            // Without an earlier `Target` nothing may keep running on this one
+            None => {
+                self.executor.deactivate();
+                Ok(())
+            }
+        };
+        self.is_local = previous_local;
+        self.target = previous_target;
+        match previous_key {
+            Some(key) => self.recipe.variables.insert(TARGET_KEY.to_string(), key),
+            None => self.recipe.variables.remove(TARGET_KEY),
+        };
+        result.and(restored)
+    }
+
+    fn resolve_become(
+        &mut self,
+        user: Option<Token>,
+        body: Vec<Statement>,
+    ) -> Result<(), ReployError> {
+        let user = match user {
+            Some(user) => self.replace_variable(user.literal)?,
+            None => DEFAULT_BECOME_USER.to_string(),
+        };
+        let previous = self.become_user.replace(user);
+        let result = self.resolve_statement(body);
+        self.become_user = previous;
+        result
+    }
+
     fn resolve_list_loop(
         &mut self,
         variable: Token,
@@ -481,22 +817,64 @@ impl Evaluator {
         Ok(())
     }
 
-    fn resolve_while(&mut self, condition: Token, body: Vec<Statement>) -> Result<(), ReployError> {
-        // Handle "true" constant for infinite loop
-        if condition.literal == "true" {
-            while !self.is_end {
-                self.resolve_statement(body.clone())?;
-            }
-            return Ok(());
-        }
+    fn resolve_while(
+        &mut self,
+        condition: Token,
+        max: Option<Token>,
+        timeout: Option<Token>,
+        body: Vec<Statement>,
+    ) -> Result<(), ReployError> {
+        let max_iterations = match max {
+            Some(t) => Some(
+                self.replace_variable(t.literal.clone())?
+                    .parse::<u64>()
+                    .map_err(|_| {
+                        ReployError::Runtime(format!(
+                            "Line {}: Invalid WHILE iteration limit: {}",
+                            t.line_num, t.literal
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+        let time_limit = match timeout {
+            Some(t) => Some(
+                util::parse_duration(&self.replace_variable(t.literal.clone())?)
+                    .map_err(|e| ReployError::Runtime(format!("Line {}: {}", t.line_num, e)))?,
+            ),
+            None => None,
+        };
 
-        // Evaluate condition with variable substitution
+        let start = std::time::Instant::now();
+        let mut iterations: u64 = 0;
         loop {
-            let cond =
-                util::evaluate_expression(&self.replace_variable(condition.literal.clone())?)?;
-            if cond.to_lowercase() != "true" {
-                break;
+            // Handle "true" constant for infinite loop
+            if condition.literal != "true" {
+                // Evaluate condition with variable substitution
+                let cond =
+                    util::evaluate_expression(&self.replace_variable(condition.literal.clone())?)?;
+                if cond.to_lowercase() != "true" {
+                    break;
+                }
+            }
+
+            if let Some(limit) = max_iterations
+                && iterations >= limit
+            {
+                return Err(ReployError::Runtime(format!(
+                    "Line {}: WHILE loop exceeded the maximum of {} iterations",
+                    condition.line_num, limit
+                )));
+            }
+            if let Some(limit) = time_limit
+                && start.elapsed() >= limit
+            {
+                return Err(ReployError::Runtime(format!(
+                    "Line {}: WHILE loop timed out after {:?}",
+                    condition.line_num, limit
+                )));
             }
+            iterations += 1;
 
             self.resolve_statement(body.clone())?;
 
@@ -1,40 +1,163 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
-use std::io::{BufReader, BufWriter, Read};
-use std::net::TcpStream;
-use std::path::Path;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ssh2::{CheckResult, ErrorCode, HashType, HostKeyType, KnownHostFileKind};
 
 use super::Stdio;
+use super::auth::Authenticator;
 use super::error::ReployError;
+use super::keepalive::Keepalive;
+use super::output::{self, Capture, Captured, Echo};
+use super::shell;
+use super::ssh_config::SshConfig;
+use super::sudo::{self, PromptWatcher};
+use super::target::{self, Target};
+use super::terminal::{self, RawMode};
+use super::tunnel;
 
 use super::util;
 
 const BUF_SIZE: usize = 1024 * 1024;
 
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait for more output from a command before polling again
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often an interactive session checks whether the terminal was resized
+const RESIZE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a timed out remote command gets to exit after SIGTERM
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Prefix of the line carrying the remote shell's PID for commands with a timeout
+const PID_MARKER: &str = "reploy-pid:";
+
+/// libssh2's error code for an operation that would block
+pub(super) const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+// libssh2 error codes meaning the underlying connection is gone
+const LIBSSH2_ERROR_SOCKET_SEND: i32 = -7;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_SOCKET_TIMEOUT: i32 = -30;
+const LIBSSH2_ERROR_SOCKET_RECV: i32 = -43;
+const LIBSSH2_ERROR_BAD_SOCKET: i32 = -45;
+
 pub trait Executor {
-    fn connect(&mut self, target: &str) -> Result<(), ReployError>;
+    fn connect(&mut self, target: &str, via: Option<&str>) -> Result<(), ReployError>;
     fn disconnect(&mut self) -> Result<(), ReployError>;
-    fn execute(&mut self, command: &str) -> Result<(), ReployError>;
-    fn send(&self, source: &str, dest: &str) -> Result<(), ReployError>;
-    fn recv(&self, source: &str, dest: &str) -> Result<(), ReployError>;
+    /// Stop running commands on the connected target, keeping its session for later
+    fn deactivate(&mut self);
+    /// Whether `connect` reaches other machines, rather than every target being
+    /// the local one
+    fn is_remote(&self) -> bool;
+    /// Run `command` and capture its output for `stdio`
+    fn execute(&mut self, command: &str, options: &RunOptions) -> Result<(), ReployError>;
+    fn send(&mut self, source: &str, dest: &str) -> Result<(), ReployError>;
+    fn recv(&mut self, source: &str, dest: &str) -> Result<(), ReployError>;
+    /// Hand the user's terminal over to `command`, or to a login shell, until it exits
+    fn interact(&mut self, command: Option<&str>) -> Result<(), ReployError>;
     fn stdio(&self) -> &Stdio;
 }
 
+/// How `Executor::execute` runs a command
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    /// Echo output line by line as it arrives, prefixed with this
+    pub echo: Option<String>,
+    /// Kill the command once it has run for this long
+    pub timeout: Option<Duration>,
+    /// Run the command as this user through sudo
+    pub become_user: Option<String>,
+    /// Directory to run the command in
+    pub cwd: Option<String>,
+    /// Environment variables to set for the command
+    pub env: Vec<(String, String)>,
+    /// Run a remote command on a pseudo-terminal, which merges stderr into
+    /// stdout. Local commands run without one.
+    pub pty: bool,
+    /// Data to feed the command on stdin, which is closed afterwards
+    pub stdin: Option<StdinSource>,
+    /// Keep at most this many bytes of each stream in memory and spill the
+    /// rest to a temporary file
+    pub limit: Option<usize>,
+    /// Write stdout to this local file rather than keeping it in memory
+    pub save_stdout: Option<PathBuf>,
+    /// Interpreter and options the command is appended to, such as
+    /// `bash -euo pipefail -c`; `sh -c` locally and the login shell remotely
+    /// when unset
+    pub shell: Option<Vec<String>>,
+}
+
+impl RunOptions {
+    /// Where the command's stdout and stderr are collected
+    fn captures(&self) -> Result<(Capture, Capture), ReployError> {
+        let stdout = match &self.save_stdout {
+            Some(path) => Capture::to_file("stdout", path).map_err(|e| {
+                ReployError::Io(e).with_context(format!("Failed to create {}", path.display()))
+            })?,
+            None => Capture::new("stdout", self.limit),
+        };
+        Ok((stdout, Capture::new("stderr", self.limit)))
+    }
+}
+
+/// Where a command's stdin comes from
+#[derive(Clone, Debug)]
+pub enum StdinSource {
+    Data(String),
+    File(PathBuf),
+}
+
+impl StdinSource {
+    fn open(&self) -> Result<Box<dyn Read + Send>, ReployError> {
+        match self {
+            StdinSource::Data(data) => Ok(Box::new(io::Cursor::new(data.clone().into_bytes()))),
+            StdinSource::File(path) => File::open(path).map(|f| Box::new(f) as _).map_err(|e| {
+                ReployError::Io(e).with_context(format!("Failed to open {}", path.display()))
+            }),
+        }
+    }
+}
+
+/// What watching sudo's prompt tells the thread feeding a command's stdin
+enum Feed {
+    Password,
+    Ready,
+}
+
 #[derive(Debug)]
 pub struct LocalExecutor {
     stdio: Stdio,
+    become_password: Option<String>,
 }
 
 impl LocalExecutor {
     pub fn new() -> Self {
         LocalExecutor {
             stdio: Stdio::default(),
+            become_password: None,
         }
     }
+
+    pub fn set_become_password(&mut self, password: &str) {
+        self.become_password = Some(password.to_string());
+    }
 }
 
 impl Executor for LocalExecutor {
-    fn connect(&mut self, _target: &str) -> Result<(), ReployError> {
+    fn connect(&mut self, _target: &str, _via: Option<&str>) -> Result<(), ReployError> {
         Ok(())
     }
 
@@ -42,161 +165,750 @@ impl Executor for LocalExecutor {
         Ok(())
     }
 
-    fn execute(&mut self, command: &str) -> Result<(), ReployError> {
-        use std::process::Command;
+    fn deactivate(&mut self) {}
 
-        let output = if cfg!(target_os = "windows") {
-            Command::new("cmd")
-                .args(&["/C", command])
-                .output()
-                .map_err(|e| {
-                    ReployError::CommandFailed(
-                        -1,
-                        format!(
-                            "Failed to execute command on Windows: {}, Error: {}",
-                            command, e
-                        ),
-                    )
-                })?
+    fn is_remote(&self) -> bool {
+        false
+    }
+
+    fn execute(&mut self, command: &str, options: &RunOptions) -> Result<(), ReployError> {
+        use std::process::{Command, Stdio as Pipe};
+
+        let platform = if cfg!(target_os = "windows") {
+            "Windows"
         } else {
-            Command::new("sh")
-                .arg("-c")
-                .arg(command)
-                .output()
-                .map_err(|e| {
-                    ReployError::CommandFailed(
-                        -1,
-                        format!(
-                            "Failed to execute command on Unix: {}, Error: {}",
-                            command, e
-                        ),
-                    )
-                })?
+            "Unix"
+        };
+        let wrapped;
+        let argv: Vec<&str> = match (&options.become_user, &options.shell) {
+            // sudo resets the environment, so it is exported inside the command instead
+            (Some(user), interpreter) => {
+                let command = match interpreter {
+                    Some(interpreter) => shell::invoke(interpreter, command),
+                    None => command.to_string(),
+                };
+                wrapped = sudo::wrap(&shell::script(&command, None, &options.env), user, false);
+                vec!["sh", "-c", &wrapped]
+            }
+            (None, Some(interpreter)) => interpreter
+                .iter()
+                .map(String::as_str)
+                .chain([command])
+                .collect(),
+            (None, None) if cfg!(target_os = "windows") => vec!["cmd", "/C", command],
+            (None, None) => vec!["sh", "-c", command],
+        };
+        let mut cmd = Command::new(argv[0]);
+        cmd.args(&argv[1..]);
+        // A process group of its own lets a timeout kill everything it started
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        if let Some(cwd) = &options.cwd {
+            cmd.current_dir(expand_home(cwd));
+        }
+        if options.become_user.is_none() {
+            cmd.envs(options.env.iter().map(|(k, v)| (k, v)));
+        }
+        // sudo reads the become password from stdin
+        let input = options.stdin.as_ref().map(|i| i.open()).transpose()?;
+        let (stdout_capture, stderr_capture) = options.captures()?;
+        let stdin = match (&options.become_user, &self.become_password, &input) {
+            (Some(_), Some(_), _) | (_, _, Some(_)) => Pipe::piped(),
+            _ => Pipe::null(),
+        };
+        let mut child = cmd
+            .stdin(stdin)
+            .stdout(Pipe::piped())
+            .stderr(Pipe::piped())
+            .spawn()
+            .map_err(|e| {
+                ReployError::CommandFailed(
+                    -1,
+                    format!(
+                        "Failed to execute command on {}: {}, Error: {}",
+                        platform, command, e
+                    ),
+                )
+            })?;
+
+        // Drain both pipes at once so that neither can fill up and stall the command
+        let echo = options.echo.as_deref();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let stdin = child.stdin.take();
+        let password = self.become_password.as_deref();
+        let mut watcher = options
+            .become_user
+            .as_ref()
+            .map(|_| PromptWatcher::new(password.is_some()));
+        // Watch stderr for sudo's prompt and tell the stdin feeder to answer it.
+        // Failing drops the sender, which makes the feeder close stdin and stop sudo.
+        let (events, feed) = mpsc::channel::<Feed>();
+        let mut events = Some(events);
+        let answer_prompt = move |chunk: &[u8]| -> Result<Vec<u8>, ReployError> {
+            let Some(watcher) = watcher.as_mut() else {
+                return Ok(chunk.to_vec());
+            };
+            if chunk.is_empty() {
+                return Ok(watcher.finish());
+            }
+            let (chunk, answer) = watcher.feed(chunk)?;
+            if let Some(sender) = &events {
+                if answer {
+                    let _ = sender.send(Feed::Password);
+                }
+                if watcher.is_ready() {
+                    let _ = sender.send(Feed::Ready);
+                    events = None;
+                }
+            }
+            Ok(chunk)
         };
+        let is_become = options.become_user.is_some();
+        let (stdout, stderr, status, fed) = thread::scope(|scope| {
+            // Input goes to the command only once sudo is done reading its password
+            let fed = scope.spawn(move || -> io::Result<()> {
+                let Some(mut pipe) = stdin else {
+                    return Ok(());
+                };
+                // Without a Ready the command never started, so it gets nothing
+                if is_become {
+                    loop {
+                        match feed.recv() {
+                            Ok(Feed::Password) => {
+                                writeln!(pipe, "{}", password.unwrap_or_default())?
+                            }
+                            Ok(Feed::Ready) => break,
+                            Err(_) => return Ok(()),
+                        }
+                    }
+                }
+                if let Some(mut input) = input {
+                    io::copy(&mut input, &mut pipe)?;
+                }
+                Ok(())
+            });
+            let stdout = scope.spawn(|| {
+                let echo = echo.map(|p| Echo::new(p, false));
+                output::capture(stdout, stdout_capture, echo, |chunk| Ok(chunk.to_vec()))
+            });
+            let stderr = scope.spawn(|| {
+                let echo = echo.map(|p| Echo::new(p, true));
+                output::capture(stderr, stderr_capture, echo, answer_prompt)
+            });
+            let escalation = options.become_user.as_deref().map(|user| (user, password));
+            let status = wait_child(&mut child, command, options.timeout, escalation);
+            (
+                stdout.join().unwrap(),
+                stderr.join().unwrap(),
+                status,
+                fed.join().unwrap(),
+            )
+        });
+        let (status, stdout, stderr) = (status?, stdout?, stderr?);
+        match fed {
+            // The command does not have to read all of its input
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                return Err(ReployError::Io(e).with_context("Failed to feed stdin"));
+            }
+            _ => {}
+        }
 
+        let ((stdout, stdout_file), (stderr, stderr_file)) = (stdout, stderr);
         self.stdio = Stdio {
-            exit_code: output.status.code().unwrap_or(-1),
-            stdout: String::from_utf8_lossy(&output.stdout).trim().to_string(),
-            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            exit_code: status.code().unwrap_or(-1),
+            stdout,
+            stderr,
+            stdout_file,
+            stderr_file,
         };
 
         Ok(())
     }
 
-    fn send(&self, source: &str, dest: &str) -> Result<(), ReployError> {
+    fn send(&mut self, source: &str, dest: &str) -> Result<(), ReployError> {
         let source_path = Path::new(source);
         let dest_path = Path::new(dest);
         std::fs::copy(source_path, dest_path)?;
         Ok(())
     }
 
-    fn recv(&self, source: &str, dest: &str) -> Result<(), ReployError> {
+    fn recv(&mut self, source: &str, dest: &str) -> Result<(), ReployError> {
         let source_path = Path::new(source);
         let dest_path = Path::new(dest);
         std::fs::copy(source_path, dest_path)?;
         Ok(())
     }
 
+    fn interact(&mut self, command: Option<&str>) -> Result<(), ReployError> {
+        use std::process::Command;
+
+        // The child inherits the terminal, so there is nothing to forward
+        let mut cmd = match (command, cfg!(target_os = "windows")) {
+            (Some(command), true) => {
+                let mut cmd = Command::new("cmd");
+                cmd.args(["/C", command]);
+                cmd
+            }
+            (Some(command), false) => {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(command);
+                cmd
+            }
+            (None, true) => Command::new("cmd"),
+            (None, false) => Command::new(std::env::var("SHELL").unwrap_or_else(|_| "sh".into())),
+        };
+        let status = {
+            // Prompts share the terminal, so none may show while the user has it
+            let _prompt = util::prompt_lock();
+            cmd.status()?
+        };
+        self.stdio = Stdio {
+            exit_code: status.code().unwrap_or(-1),
+            ..Stdio::default()
+        };
+        Ok(())
+    }
+
     fn stdio(&self) -> &Stdio {
         &self.stdio
     }
 }
 
-pub struct SshExecutor {
+/// How the server's host key is checked against the known_hosts file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostKeyPolicy {
+    /// Refuse unknown and changed host keys
+    Strict,
+    /// Record unknown host keys, refuse changed ones
+    AcceptNew,
+    /// Skip host key verification entirely
+    Off,
+}
+
+impl FromStr for HostKeyPolicy {
+    type Err = ReployError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(HostKeyPolicy::Strict),
+            "accept-new" => Ok(HostKeyPolicy::AcceptNew),
+            "off" => Ok(HostKeyPolicy::Off),
+            _ => Err(ReployError::Runtime(format!(
+                "Invalid host key policy: {}, expected 'strict', 'accept-new' or 'off'",
+                s
+            ))),
+        }
+    }
+}
+
+/// A session to one target along with the jump host sessions it is
+/// tunnelled through
+struct Connection {
     session: ssh2::Session,
+    tunnels: Vec<ssh2::Session>,
+    keepalive: Option<Keepalive>,
+    target: String,
+    via: Option<String>,
+}
+
+impl Connection {
+    fn is_lost(&self) -> bool {
+        self.keepalive.as_ref().is_some_and(|k| k.is_lost())
+    }
+
+    fn close(mut self) -> Result<(), ReployError> {
+        self.keepalive = None;
+        let result = self.session.disconnect(None, "connection closing", None);
+        // Innermost hops first; errors are moot once the target is gone
+        while let Some(jump) = self.tunnels.pop() {
+            let _ = jump.disconnect(None, "connection closing", None);
+        }
+        Ok(result?)
+    }
+}
+
+/// Runs commands over SSH, keeping one session per target so that a recipe
+/// can switch back and forth between hosts without reconnecting
+pub struct SshExecutor {
+    connections: HashMap<String, Connection>,
+    active: Option<String>,
     stdio: Stdio,
-    identity: std::path::PathBuf,
-    password: Option<String>,
+    authenticator: Authenticator,
+    ssh_config: PathBuf,
+    host_key_policy: HostKeyPolicy,
+    known_hosts: PathBuf,
+    jump: Option<String>,
+    connect_timeout: Duration,
+    handshake_timeout: Duration,
+    keepalive_interval: Option<Duration>,
+    become_password: Option<String>,
 }
 
 impl SshExecutor {
     pub fn new() -> Self {
         SshExecutor {
-            session: ssh2::Session::new().unwrap(),
+            connections: HashMap::new(),
+            active: None,
             stdio: Stdio::default(),
-            identity: util::ssh_key(),
-            password: None,
+            authenticator: Authenticator::new(),
+            ssh_config: util::ssh_config(),
+            host_key_policy: HostKeyPolicy::Strict,
+            known_hosts: util::known_hosts(),
+            jump: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            become_password: None,
         }
     }
 
-    pub fn set_identity(&mut self, identity: &str) {
-        self.identity = std::path::PathBuf::from(identity);
+    pub fn add_identity(&mut self, identity: &str) {
+        self.authenticator.add_identity(PathBuf::from(identity));
+    }
+
+    pub fn set_ssh_config(&mut self, path: &str) {
+        self.ssh_config = PathBuf::from(path);
     }
 
     pub fn set_password(&mut self, password: &str) {
-        self.password = Some(password.to_string());
+        self.authenticator.set_password(password);
     }
-}
 
-impl Executor for SshExecutor {
-    fn connect(&mut self, target: &str) -> Result<(), ReployError> {
-        let mut user = "root";
-        let mut port = "22";
-        let mut host;
-
-        if target.contains("@") {
-            let v: Vec<&str> = target.split("@").collect();
-            user = v[0];
-            host = v[1];
-        } else {
-            host = target;
+    pub fn set_host_key_policy(&mut self, policy: HostKeyPolicy) {
+        self.host_key_policy = policy;
+    }
+
+    pub fn set_known_hosts(&mut self, path: &str) {
+        self.known_hosts = PathBuf::from(path);
+    }
+
+    /// Reach every target through these comma-separated jump hosts unless
+    /// the recipe names its own with `Via`
+    pub fn set_jump(&mut self, jump: &str) {
+        self.jump = Some(jump.to_string());
+    }
+
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// A zero interval disables keepalives
+    pub fn set_keepalive_interval(&mut self, interval: Duration) {
+        self.keepalive_interval = Some(interval).filter(|i| !i.is_zero());
+    }
+
+    pub fn set_become_password(&mut self, password: &str) {
+        self.become_password = Some(password.to_string());
+    }
+
+    /// Open an authenticated session to `target`, either directly or tunnelled
+    /// through the session of the previous hop
+    fn open_session(
+        &mut self,
+        target: &str,
+        config: &SshConfig,
+        through: Option<&ssh2::Session>,
+    ) -> Result<ssh2::Session, ReployError> {
+        let target = target.parse::<Target>()?;
+
+        // Resolve aliases, users, ports and identities the same way `ssh` does
+        let host_config = config.resolve(&target.host);
+        let host = host_config.host_name.as_deref().unwrap_or(&target.host);
+        let user = target
+            .user
+            .as_deref()
+            .or(host_config.user.as_deref())
+            .unwrap_or("root");
+        let port = target.port.or(host_config.port).unwrap_or(22);
+
+        let tcp_stream = match through {
+            Some(jump) => tunnel::open(jump, host, port)?,
+            None => connect_tcp(host, port, self.connect_timeout)?,
+        };
+        let mut session = ssh2::Session::new()?;
+        session.set_tcp_stream(tcp_stream);
+        session.set_timeout(self.handshake_timeout.as_millis().min(u32::MAX as u128) as u32);
+        session.handshake().map_err(|e| {
+            ReployError::Ssh(e).with_context(format!(
+                "SSH handshake with {} failed",
+                target::host_port(host, port)
+            ))
+        })?;
+        self.verify_host_key(&session, host, port)?;
+        // Commands may legitimately run for a long time
+        session.set_timeout(0);
+
+        self.authenticator
+            .authenticate(&session, user, &host_config.identity_files)?;
+
+        Ok(session)
+    }
+
+    /// Open a connection to `target`, building the chain of jump hosts first
+    fn open_connection(
+        &mut self,
+        target: &str,
+        via: Option<&str>,
+    ) -> Result<Connection, ReployError> {
+        let config = SshConfig::load(&self.ssh_config)?;
+        let host = target.parse::<Target>()?.host;
+
+        // A recipe's Via wins over --jump, which wins over ProxyJump in the SSH config
+        let jumps = via
+            .map(|v| v.to_string())
+            .or_else(|| self.jump.clone())
+            .or_else(|| config.resolve(&host).proxy_jump)
+            .filter(|j| j != "none")
+            .unwrap_or_default();
+
+        let mut tunnels = Vec::new();
+        let mut previous: Option<ssh2::Session> = None;
+        for hop in jumps.split(',').map(|h| h.trim()).filter(|h| !h.is_empty()) {
+            let session = self
+                .open_session(hop, &config, previous.as_ref())
+                .map_err(|e| e.with_context(format!("Failed to connect to jump host {}", hop)))?;
+            tunnels.extend(previous.replace(session));
         }
+        let session = self.open_session(target, &config, previous.as_ref())?;
+        tunnels.extend(previous);
+        let keepalive = self
+            .keepalive_interval
+            .map(|interval| Keepalive::start(&session, interval));
+
+        Ok(Connection {
+            session,
+            tunnels,
+            keepalive,
+            target: target.to_string(),
+            via: via.map(|v| v.to_string()),
+        })
+    }
 
-        if host.contains(":") {
-            let v: Vec<&str> = host.split(":").collect();
-            host = v[0];
-            port = v[1];
+    /// Replace the pooled connection under `key` after it was lost
+    fn reconnect(&mut self, key: &str) -> Result<(), ReployError> {
+        let stale = self
+            .connections
+            .remove(key)
+            .ok_or(ReployError::ConnectionFailed)?;
+        eprintln!("Connection to {} lost, reconnecting...", stale.target);
+        let (target, via) = (stale.target.clone(), stale.via.clone());
+        let _ = stale.close();
+        let connection = self.open_connection(&target, via.as_deref())?;
+        self.connections.insert(key.to_string(), connection);
+        Ok(())
+    }
+
+    fn session(&self) -> Result<&ssh2::Session, ReployError> {
+        self.active
+            .as_ref()
+            .and_then(|key| self.connections.get(key))
+            .map(|connection| &connection.session)
+            .ok_or_else(|| {
+                ReployError::Runtime(
+                    "No target connected, add a Target statement first".to_string(),
+                )
+            })
+    }
+
+    /// Terminate the process group of a timed out command from a second channel,
+    /// following up with SIGKILL for anything that ignores SIGTERM. For a command
+    /// run through sudo the kill is repeated through sudo, as the login user may
+    /// not signal the processes it started.
+    fn kill_remote(&self, pid: u32, become_user: Option<&str>) {
+        let Ok(session) = self.session() else {
+            return;
+        };
+        let Ok(mut channel) = session.channel_session() else {
+            return;
+        };
+        let kill = format!(
+            "kill -TERM -- -{pid} 2>/dev/null || kill -TERM {pid} 2>/dev/null; \
+             (sleep {grace}; kill -KILL -- -{pid} 2>/dev/null) >/dev/null 2>&1 &",
+            pid = pid,
+            grace = KILL_GRACE.as_secs()
+        );
+        let script = match become_user {
+            Some(user) => format!(
+                "{}\n{} >/dev/null 2>&1",
+                kill,
+                sudo::wrap(&kill, user, false)
+            ),
+            None => kill,
+        };
+        if channel.exec(&script).is_err() {
+            return;
+        }
+        // sudo may ask for the password again on this channel
+        if become_user.is_some()
+            && let Some(password) = &self.become_password
+        {
+            let _ = writeln!(channel, "{}", password);
         }
+        let _ = channel.send_eof();
+        let _ = channel.wait_close();
+    }
 
-        let tcp_stream = TcpStream::connect(format!("{}:{}", host, port)).map_err(|e| {
-            ReployError::ConnectionFailed.with_context(format!(
-                "Failed to connect to {}:{},Error: {}",
-                host, port, e
-            ))
+    /// Run `op` against the active session. If the connection turns out to
+    /// have been lost while idle, reconnect and retry once; `op` must only
+    /// open a channel so that nothing can run twice.
+    fn with_session<T>(
+        &mut self,
+        op: impl Fn(&ssh2::Session) -> Result<T, ssh2::Error>,
+    ) -> Result<T, ReployError> {
+        let key = self.active.clone().ok_or_else(|| {
+            ReployError::Runtime("No target connected, add a Target statement first".to_string())
         })?;
-        self.session.set_tcp_stream(tcp_stream);
-        self.session.handshake()?;
-
-        if let Some(password) = &self.password {
-            self.session.userauth_password(user, password)?;
-        } else if self.identity.exists() {
-            self.session
-                .userauth_pubkey_file(user, None, &self.identity, None)?;
+        if self.connections[&key].is_lost() {
+            self.reconnect(&key)?;
+        }
+        match op(&self.connections[&key].session) {
+            Err(e) if is_connection_lost(&e) => {
+                self.reconnect(&key)?;
+                Ok(op(&self.connections[&key].session)?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Append one `known_hosts` line, creating the file and its directory if needed
+    fn append_known_host(&self, line: &str) -> io::Result<()> {
+        if let Some(dir) = self.known_hosts.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&self.known_hosts)?;
+        // Do not glue the entry onto a last line without a newline
+        let mut last = [0u8];
+        let len = file.metadata()?.len();
+        if len > 0 {
+            io::Seek::seek(&mut file, io::SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+        }
+        let separator = if len > 0 && last[0] != b'\n' {
+            "\n"
         } else {
-            return Err(ReployError::Io(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("No authentication method available (no password or identity file)"),
-            )));
+            ""
+        };
+        writeln!(file, "{}{}", separator, line)
+    }
+
+    fn verify_host_key(
+        &self,
+        session: &ssh2::Session,
+        host: &str,
+        port: u16,
+    ) -> Result<(), ReployError> {
+        if self.host_key_policy == HostKeyPolicy::Off {
+            return Ok(());
         }
 
-        if !self.session.authenticated() {
-            return Err(ReployError::AuthFailed);
+        let (key, key_type) = session.host_key().ok_or_else(|| {
+            ReployError::HostKeyVerificationFailed(format!("{} did not offer a host key", host))
+        })?;
+        let fingerprint = session
+            .host_key_hash(HashType::Sha256)
+            .map(|hash| format!("SHA256:{}", util::base64_encode(hash)))
+            .unwrap_or_default();
+
+        // Sessions opened in parallel share the file, so hold the lock from
+        // reading it until any new entry has been appended
+        static KNOWN_HOSTS: Mutex<()> = Mutex::new(());
+        let _lock = KNOWN_HOSTS.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut known_hosts = session.known_hosts()?;
+        if self.known_hosts.exists() {
+            known_hosts
+                .read_file(&self.known_hosts, KnownHostFileKind::OpenSSH)
+                .map_err(|e| {
+                    ReployError::Ssh(e).with_context(format!(
+                        "Failed to read known hosts file: {}",
+                        self.known_hosts.display()
+                    ))
+                })?;
+        }
+
+        match known_hosts.check_port(host, port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(ReployError::HostKeyVerificationFailed(format!(
+                "the {} key offered by {} ({}) does not match the one recorded in {}. \
+                 Someone could be eavesdropping on you (man-in-the-middle attack), \
+                 or the host key has just been changed.",
+                key_type_name(key_type),
+                host,
+                fingerprint,
+                self.known_hosts.display()
+            ))),
+            CheckResult::NotFound if self.host_key_policy == HostKeyPolicy::AcceptNew => {
+                let entry = if port == 22 {
+                    host.to_string()
+                } else {
+                    format!("[{}]:{}", host, port)
+                };
+                // Format only the new entry and append it, rather than
+                // rewriting the file and losing its comments
+                let mut added = session.known_hosts()?;
+                added.add(&entry, key, "", key_type.into())?;
+                let line = match added.hosts()?.first() {
+                    Some(h) => added.write_string(h, KnownHostFileKind::OpenSSH)?,
+                    None => return Ok(()),
+                };
+                self.append_known_host(line.trim_end()).map_err(|e| {
+                    ReployError::Io(e).with_context(format!(
+                        "Failed to write known hosts file: {}",
+                        self.known_hosts.display()
+                    ))
+                })?;
+                println!(
+                    "Permanently added '{}' ({}, {}) to the list of known hosts.",
+                    entry,
+                    key_type_name(key_type),
+                    fingerprint
+                );
+                Ok(())
+            }
+            CheckResult::NotFound => Err(ReployError::HostKeyVerificationFailed(format!(
+                "no host key is known for {} in {}; the server offered a {} key ({}). \
+                 Verify the fingerprint and add it with ssh-keyscan, \
+                 or use --host-key-checking accept-new",
+                host,
+                self.known_hosts.display(),
+                key_type_name(key_type),
+                fingerprint
+            ))),
+            CheckResult::Failure => Err(ReployError::HostKeyVerificationFailed(format!(
+                "failed to check the host key of {} against {}",
+                host,
+                self.known_hosts.display()
+            ))),
         }
+    }
+}
+
+fn key_type_name(key_type: HostKeyType) -> &'static str {
+    match key_type {
+        HostKeyType::Rsa => "ssh-rsa",
+        HostKeyType::Dss => "ssh-dss",
+        HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+        HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+        HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+        HostKeyType::Ed25519 => "ssh-ed25519",
+        HostKeyType::Unknown => "unknown",
+    }
+}
 
+impl Executor for SshExecutor {
+    fn connect(&mut self, target: &str, via: Option<&str>) -> Result<(), ReployError> {
+        let key = match via {
+            Some(via) => format!("{} via {}", target, via),
+            None => target.to_string(),
+        };
+        // Switching back to a host reuses its session while it is still alive
+        match self.connections.get(&key) {
+            Some(connection) if !connection.is_lost() => {}
+            _ => {
+                if let Some(stale) = self.connections.remove(&key) {
+                    let _ = stale.close();
+                }
+                let connection = self.open_connection(target, via)?;
+                self.connections.insert(key.clone(), connection);
+            }
+        }
+        self.active = Some(key);
         Ok(())
     }
 
     fn disconnect(&mut self) -> Result<(), ReployError> {
-        self.session.disconnect(None, "connection closing", None)?;
-        Ok(())
+        self.active = None;
+        let mut result = Ok(());
+        for (_, connection) in self.connections.drain() {
+            let closed = connection.close();
+            if result.is_ok() {
+                result = closed;
+            }
+        }
+        result
     }
 
-    fn execute(&mut self, command: &str) -> Result<(), ReployError> {
-        let mut channel = self.session.channel_session()?;
-        channel.exec(command)?;
+    fn deactivate(&mut self) {
+        self.active = None;
+    }
 
-        let mut stdout = String::new();
-        channel.read_to_string(&mut stdout)?;
+    fn is_remote(&self) -> bool {
+        true
+    }
 
-        let mut stderr = String::new();
-        channel.stderr().read_to_string(&mut stderr)?;
+    fn execute(&mut self, command: &str, options: &RunOptions) -> Result<(), ReployError> {
+        let input = options.stdin.as_ref().map(|i| i.open()).transpose()?;
+        let captures = options.captures()?;
+        let mut channel = self.with_session(|s| s.channel_session())?;
+        // sshd only accepts the variables its AcceptEnv allows, so rather than
+        // setenv the directory and environment are set up by the command itself
+        let command_line = match &options.shell {
+            Some(interpreter) => shell::invoke(interpreter, command),
+            None => command.to_string(),
+        };
+        let script = shell::script(&command_line, options.cwd.as_deref(), &options.env);
+        let wrapped = match &options.become_user {
+            Some(user) => sudo::wrap(&script, user, options.pty),
+            None => script,
+        };
+        if options.pty {
+            let (columns, rows) = terminal::size().unwrap_or(terminal::DEFAULT_SIZE);
+            channel.request_pty(&terminal::term(), None, Some((columns, rows, 0, 0)))?;
+        }
+        match options.timeout {
+            // Report the shell's PID, which leads the process group the command runs in
+            Some(_) => channel.exec(&format!("echo \"{}$$\" >&2\n{}", PID_MARKER, wrapped))?,
+            None => channel.exec(&wrapped)?,
+        }
+
+        // Poll both streams without blocking so that output is echoed as it
+        // arrives and a chatty stderr cannot stall stdout
+        let session = self.session()?.clone();
+        session.set_blocking(false);
+        let password = self.become_password.as_deref();
+        let streamed = stream_channel(&mut channel, options, password, input, captures);
+        session.set_blocking(true);
+        let (stdout, stderr) = match streamed {
+            Ok(Streamed::Finished(stdout, stderr)) => (stdout, stderr),
+            Ok(Streamed::TimedOut(pid)) => {
+                let timeout = options.timeout.unwrap_or_default();
+                if let Some(pid) = pid {
+                    self.kill_remote(pid, options.become_user.as_deref());
+                }
+                let _ = channel.close();
+                return Err(ReployError::CommandTimedOut(
+                    timeout,
+                    command.to_string(),
+                    None,
+                ));
+            }
+            Err(e) => {
+                let _ = channel.close();
+                return Err(e);
+            }
+        };
 
+        let ((stdout, stdout_file), (stderr, stderr_file)) = (stdout, stderr);
         self.stdio = Stdio {
             exit_code: channel.exit_status()?,
-            stdout: stdout.trim().to_string(),
-            stderr: stderr.trim().to_string(),
+            stdout,
+            stderr,
+            stdout_file,
+            stderr_file,
         };
-        channel.send_eof()?;
+        // Streaming the input already ended it
+        if options.stdin.is_none() {
+            channel.send_eof()?;
+        }
         channel.wait_eof()?;
         channel.close()?;
         channel.wait_close()?;
@@ -204,8 +916,8 @@ impl Executor for SshExecutor {
         Ok(())
     }
 
-    fn send(&self, source: &str, dest: &str) -> Result<(), ReployError> {
-        let sftp = self.session.sftp()?;
+    fn send(&mut self, source: &str, dest: &str) -> Result<(), ReployError> {
+        let sftp = self.with_session(|s| s.sftp())?;
         let local_path = Path::new(source);
 
         if !local_path.exists() {
@@ -242,8 +954,8 @@ impl Executor for SshExecutor {
         Ok(())
     }
 
-    fn recv(&self, source: &str, dest: &str) -> Result<(), ReployError> {
-        let sftp = self.session.sftp()?;
+    fn recv(&mut self, source: &str, dest: &str) -> Result<(), ReployError> {
+        let sftp = self.with_session(|s| s.sftp())?;
         let remote_path = Path::new(source);
         let file_stat = sftp.stat(remote_path).map_err(|e| {
             ReployError::Ssh(e).with_context(format!("Failed to stat remote file: {}", source))
@@ -275,7 +987,380 @@ impl Executor for SshExecutor {
         Ok(())
     }
 
+    fn interact(&mut self, command: Option<&str>) -> Result<(), ReployError> {
+        // Opening the channel may reconnect and ask for credentials, so the
+        // terminal is only taken over once it is open
+        let mut channel = self.with_session(|s| {
+            let mut channel = s.channel_session()?;
+            let (columns, rows) = terminal::size().unwrap_or(terminal::DEFAULT_SIZE);
+            channel.request_pty(&terminal::term(), None, Some((columns, rows, 0, 0)))?;
+            match command {
+                Some(command) => channel.exec(command)?,
+                None => channel.shell()?,
+            }
+            Ok(channel)
+        })?;
+        // Prompts share the terminal, so none may show while the user has it
+        let prompt = util::prompt_lock();
+        let Some(raw_mode) = RawMode::enable() else {
+            let _ = channel.close();
+            return Err(ReployError::Runtime(
+                "Interact needs a terminal on stdin".to_string(),
+            ));
+        };
+
+        let session = self.session()?.clone();
+        session.set_blocking(false);
+        let forwarded = forward_terminal(&mut channel);
+        session.set_blocking(true);
+        drop(raw_mode);
+        drop(prompt);
+        forwarded?;
+
+        self.stdio = Stdio {
+            exit_code: channel.exit_status()?,
+            ..Stdio::default()
+        };
+        channel.close()?;
+        channel.wait_close()?;
+        Ok(())
+    }
+
     fn stdio(&self) -> &Stdio {
         &self.stdio
     }
 }
+
+/// How a remote command's output stream ended
+enum Streamed {
+    Finished(Captured, Captured),
+    /// The command ran past its timeout; carries its process group if known
+    TimedOut(Option<u32>),
+}
+
+/// Read a running command's stdout and stderr until both are closed, on a
+/// session in non-blocking mode, answering sudo's prompt with `password` and
+/// feeding it `input` followed by EOF
+fn stream_channel(
+    channel: &mut ssh2::Channel,
+    options: &RunOptions,
+    password: Option<&str>,
+    mut input: Option<Box<dyn Read + Send>>,
+    (mut stdout, mut stderr): (Capture, Capture),
+) -> Result<Streamed, ReployError> {
+    let echo = options.echo.as_deref();
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut pid: Option<u32> = None;
+    // The start of the stream carrying the PID line, until that line is complete
+    let mut pid_line = deadline.map(|_| Vec::new());
+    let mut stdout_echo = echo.map(|p| Echo::new(p, false));
+    let mut stderr_echo = echo.map(|p| Echo::new(p, true));
+    let watcher = || {
+        options
+            .become_user
+            .as_ref()
+            .map(|_| PromptWatcher::new(password.is_some()))
+    };
+    let mut stdout_watcher = watcher();
+    let mut stderr_watcher = watcher();
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut pending_input = Vec::new();
+    loop {
+        let mut idle = true;
+        for (is_stderr, capture, echo, watcher) in [
+            (false, &mut stdout, &mut stdout_echo, &mut stdout_watcher),
+            (true, &mut stderr, &mut stderr_echo, &mut stderr_watcher),
+        ] {
+            let read = if is_stderr {
+                channel.stderr().read(&mut buf)
+            } else {
+                channel.read(&mut buf)
+            };
+            match read {
+                Ok(0) => {}
+                Ok(n) => {
+                    idle = false;
+                    let mut chunk = match watcher.as_mut() {
+                        Some(watcher) => {
+                            let (chunk, answer) = watcher.feed(&buf[..n])?;
+                            if answer {
+                                let line = format!("{}\n", password.unwrap_or_default());
+                                tunnel::write_all(channel, line.as_bytes())?;
+                            }
+                            chunk
+                        }
+                        None => buf[..n].to_vec(),
+                    };
+                    // Strip the PID line reported ahead of a command with a timeout,
+                    // which a PTY delivers on stdout
+                    if is_stderr != options.pty
+                        && let Some(line) = pid_line.as_mut()
+                    {
+                        line.extend_from_slice(&chunk);
+                        let Some(end) = line.iter().position(|b| *b == b'\n') else {
+                            continue;
+                        };
+                        chunk = line.split_off(end + 1);
+                        let line = String::from_utf8_lossy(&line[..end]).to_string();
+                        pid = line.strip_prefix(PID_MARKER).and_then(|p| p.parse().ok());
+                        pid_line = None;
+                    }
+                    capture.write(&chunk)?;
+                    if let Some(echo) = echo.as_mut() {
+                        echo.write(&chunk);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(ReployError::Io(e)),
+            }
+        }
+        // Input goes to the command only once sudo is done reading its password
+        let started = options.become_user.is_none()
+            || [&stdout_watcher, &stderr_watcher]
+                .into_iter()
+                .flatten()
+                .any(|w| w.is_ready());
+        if let Some(reader) = input.as_mut()
+            && started
+        {
+            if pending_input.is_empty() {
+                let n = reader.read(&mut buf)?;
+                pending_input.extend_from_slice(&buf[..n]);
+            }
+            if pending_input.is_empty() {
+                match channel.send_eof() {
+                    Ok(()) => input = None,
+                    Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => {}
+                    Err(e) => return Err(ReployError::Ssh(e)),
+                }
+            } else {
+                match channel.write(&pending_input) {
+                    Ok(n) => {
+                        idle = false;
+                        pending_input.drain(..n);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    // The command does not have to read all of its input
+                    Err(_) => input = None,
+                }
+            }
+        }
+        if idle {
+            if channel.eof() {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                finish_stream(&mut stdout, &mut stdout_echo, &mut stdout_watcher)?;
+                finish_stream(&mut stderr, &mut stderr_echo, &mut stderr_watcher)?;
+                return Ok(Streamed::TimedOut(pid));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    finish_stream(&mut stdout, &mut stdout_echo, &mut stdout_watcher)?;
+    finish_stream(&mut stderr, &mut stderr_echo, &mut stderr_watcher)?;
+    Ok(Streamed::Finished(stdout.finish()?, stderr.finish()?))
+}
+
+/// Pass the user's keystrokes to an interactive channel and its output back
+/// to the terminal until it closes, keeping the remote PTY the terminal's size
+fn forward_terminal(channel: &mut ssh2::Channel) -> Result<(), ReployError> {
+    let stop = AtomicBool::new(false);
+    let (keys, keystrokes) = mpsc::channel::<Vec<u8>>();
+    thread::scope(|scope| {
+        // Raw mode lets reads time out, so the reader notices when to stop
+        scope.spawn(|| {
+            let mut stdin = io::stdin();
+            let mut buf = [0u8; 1024];
+            while !stop.load(Ordering::Relaxed) {
+                match stdin.read(&mut buf) {
+                    Ok(0) => {}
+                    Ok(n) => {
+                        if keys.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let result = (|| {
+            let mut size = terminal::size();
+            let mut resized = Instant::now();
+            let mut buf = vec![0u8; BUF_SIZE];
+            loop {
+                let mut idle = true;
+                while let Ok(data) = keystrokes.try_recv() {
+                    idle = false;
+                    tunnel::write_all(channel, &data)?;
+                }
+                for is_stderr in [false, true] {
+                    let read = if is_stderr {
+                        channel.stderr().read(&mut buf)
+                    } else {
+                        channel.read(&mut buf)
+                    };
+                    match read {
+                        Ok(0) => {}
+                        Ok(n) if is_stderr => {
+                            idle = false;
+                            io::stderr().write_all(&buf[..n])?;
+                            io::stderr().flush()?;
+                        }
+                        Ok(n) => {
+                            idle = false;
+                            io::stdout().write_all(&buf[..n])?;
+                            io::stdout().flush()?;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(e) => return Err(ReployError::Io(e)),
+                    }
+                }
+                if idle {
+                    if channel.eof() {
+                        return Ok(());
+                    }
+                    if resized.elapsed() >= RESIZE_INTERVAL {
+                        resized = Instant::now();
+                        let current = terminal::size();
+                        if let Some((columns, rows)) = current
+                            && current != size
+                        {
+                            size = current;
+                            let _ = channel.request_pty_size(columns, rows, None, None);
+                        }
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        })();
+        stop.store(true, Ordering::Relaxed);
+        result
+    })
+}
+
+/// Flush what a stream's prompt watcher held back and its last partial line
+fn finish_stream(
+    capture: &mut Capture,
+    echo: &mut Option<Echo>,
+    watcher: &mut Option<PromptWatcher>,
+) -> io::Result<()> {
+    let rest = watcher.as_mut().map(|w| w.finish()).unwrap_or_default();
+    capture.write(&rest)?;
+    if let Some(echo) = echo.as_mut() {
+        echo.write(&rest);
+        echo.finish();
+    }
+    Ok(())
+}
+
+/// Expand a leading `~` in a local path to the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), util::home_dir()) {
+        (Some(""), Some(home)) => home,
+        (Some(rest), Some(home)) if rest.starts_with('/') => home.join(&rest[1..]),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Wait for a local command, killing its process group once `timeout` has passed.
+/// `escalation` is the user and password of a command run through sudo.
+fn wait_child(
+    child: &mut std::process::Child,
+    command: &str,
+    timeout: Option<Duration>,
+    escalation: Option<(&str, Option<&str>)>,
+) -> Result<std::process::ExitStatus, ReployError> {
+    let Some(timeout) = timeout else {
+        return Ok(child.wait()?);
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    #[cfg(unix)]
+    {
+        let group = format!("-{}", child.id());
+        let _ = std::process::Command::new("kill")
+            .args(["-KILL", "--", &group])
+            .status();
+        // The processes sudo started belong to another user, so kill them through sudo too
+        if let Some((user, password)) = escalation {
+            kill_as(user, password, &format!("kill -KILL -- {}", group));
+        }
+    }
+    let _ = child.kill();
+    // Give up on a process that survived rather than wait for it forever
+    let deadline = Instant::now() + KILL_GRACE;
+    while Instant::now() < deadline && matches!(child.try_wait(), Ok(None)) {
+        thread::sleep(POLL_INTERVAL);
+    }
+    Err(ReployError::CommandTimedOut(
+        timeout,
+        command.to_string(),
+        None,
+    ))
+}
+
+/// Run the shell `script` as `user` through sudo, answering its prompt with `password`
+#[cfg(unix)]
+fn kill_as(user: &str, password: Option<&str>, script: &str) {
+    use std::process::{Command, Stdio as Pipe};
+
+    let Ok(mut sudo) = Command::new("sh")
+        .args(["-c", &sudo::wrap(script, user, false)])
+        .stdin(Pipe::piped())
+        .stdout(Pipe::null())
+        .stderr(Pipe::null())
+        .spawn()
+    else {
+        return;
+    };
+    // Closing stdin makes sudo give up rather than wait for a password
+    if let (Some(mut stdin), Some(password)) = (sudo.stdin.take(), password) {
+        let _ = writeln!(stdin, "{}", password);
+    }
+    let _ = sudo.wait();
+}
+
+/// Connect to the first reachable address of `host`, giving up on each
+/// address after `timeout`
+fn connect_tcp(host: &str, port: u16, timeout: Duration) -> Result<TcpStream, ReployError> {
+    let failed = |e: io::Error| {
+        ReployError::ConnectionFailed.with_context(format!(
+            "Failed to connect to {},Error: {}",
+            target::host_port(host, port),
+            e
+        ))
+    };
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+    for addr in (host, port).to_socket_addrs().map_err(failed)? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(failed(last_error))
+}
+
+fn is_connection_lost(e: &ssh2::Error) -> bool {
+    matches!(
+        e.code(),
+        ssh2::ErrorCode::Session(
+            LIBSSH2_ERROR_SOCKET_SEND
+                | LIBSSH2_ERROR_SOCKET_DISCONNECT
+                | LIBSSH2_ERROR_SOCKET_TIMEOUT
+                | LIBSSH2_ERROR_SOCKET_RECV
+                | LIBSSH2_ERROR_BAD_SOCKET
+        )
+    )
+}
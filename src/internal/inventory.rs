@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use toml::{Table, Value};
+
+use super::error::ReployError;
+use super::target::Target;
+use super::token::{Token, Type};
+use super::{Recipe, Statement};
+
+/// Prefix of a `Target` naming inventory hosts or groups instead of an address
+pub const INVENTORY_PREFIX: char = '@';
+
+/// Selection matching every host in the inventory
+const ALL: &str = "all";
+
+/// A host selected from the inventory, with its variables already merged
+#[derive(Clone, Debug)]
+pub struct Host {
+    pub name: String,
+    pub target: String,
+    pub variables: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+struct HostEntry {
+    name: String,
+    target: String,
+    vars: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+struct Group {
+    name: String,
+    hosts: Vec<String>,
+    children: Vec<String>,
+    vars: Vec<(String, String)>,
+}
+
+/// A TOML inventory of hosts and groups:
+///
+/// ```toml
+/// [vars]
+/// app_dir = "/srv/app"
+///
+/// [hosts]
+/// web1 = "root@10.0.0.11"
+/// db1 = { target = "postgres@10.0.0.21:2222", vars = { role = "primary" } }
+///
+/// [groups.web]
+/// hosts = ["web1"]
+/// vars = { http_port = 8080 }
+///
+/// [groups.prod]
+/// children = ["web"]
+/// hosts = ["db1"]
+/// ```
+///
+/// Host variables win over group variables, which win over global ones.
+/// Between groups, children win over their parents.
+#[derive(Debug)]
+pub struct Inventory {
+    vars: Vec<(String, String)>,
+    hosts: Vec<HostEntry>,
+    groups: Vec<Group>,
+}
+
+impl Inventory {
+    pub fn load(path: &Path) -> Result<Inventory, ReployError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            ReployError::Io(e).with_context(format!("Failed to read inventory: {}", path.display()))
+        })?;
+        Inventory::parse(&content)
+            .map_err(|e| e.with_context(format!("Failed to load inventory: {}", path.display())))
+    }
+
+    fn parse(content: &str) -> Result<Inventory, ReployError> {
+        let table = content
+            .parse::<Table>()
+            .map_err(|e| ReployError::InvalidInventory(e.to_string().trim_end().to_string()))?;
+
+        let mut inventory = Inventory {
+            vars: Vec::new(),
+            hosts: Vec::new(),
+            groups: Vec::new(),
+        };
+        for (key, value) in &table {
+            match key.as_str() {
+                "vars" => inventory.vars = variables("vars", value)?,
+                "hosts" => {
+                    for (name, entry) in as_table("hosts", value)? {
+                        inventory.hosts.push(parse_host(name, entry)?);
+                    }
+                }
+                "groups" => {
+                    for (name, entry) in as_table("groups", value)? {
+                        inventory.groups.push(parse_group(name, entry)?);
+                    }
+                }
+                _ => {
+                    return Err(ReployError::InvalidInventory(format!(
+                        "Unknown section: {}",
+                        key
+                    )));
+                }
+            }
+        }
+        inventory.validate()?;
+        Ok(inventory)
+    }
+
+    fn validate(&self) -> Result<(), ReployError> {
+        for group in &self.groups {
+            if group.name == ALL || self.host(&group.name).is_some() {
+                return Err(ReployError::InvalidInventory(format!(
+                    "Group name {} is already used by a host or reserved",
+                    group.name
+                )));
+            }
+            for host in &group.hosts {
+                if self.host(host).is_none() {
+                    return Err(ReployError::InvalidInventory(format!(
+                        "Group {} refers to unknown host {}",
+                        group.name, host
+                    )));
+                }
+            }
+            for child in &group.children {
+                if self.group(child).is_none() {
+                    return Err(ReployError::InvalidInventory(format!(
+                        "Group {} refers to unknown child group {}",
+                        group.name, child
+                    )));
+                }
+            }
+            self.depth(&group.name, &mut Vec::new())?;
+        }
+        Ok(())
+    }
+
+    fn host(&self, name: &str) -> Option<&HostEntry> {
+        self.hosts.iter().find(|h| h.name == name)
+    }
+
+    fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|g| g.name == name)
+    }
+
+    /// Length of the longest chain of parents above `name`, used to apply
+    /// parent group variables before those of their children
+    fn depth(&self, name: &str, path: &mut Vec<String>) -> Result<usize, ReployError> {
+        if path.iter().any(|p| p == name) {
+            path.push(name.to_string());
+            return Err(ReployError::InvalidInventory(format!(
+                "Group cycle: {}",
+                path.join(" -> ")
+            )));
+        }
+        path.push(name.to_string());
+        let mut depth = 0;
+        for parent in self
+            .groups
+            .iter()
+            .filter(|g| g.children.iter().any(|c| c == name))
+        {
+            depth = depth.max(self.depth(&parent.name, path)? + 1);
+        }
+        path.pop();
+        Ok(depth)
+    }
+
+    /// Whether `host` belongs to `group` directly or through a child group
+    fn contains(&self, group: &Group, host: &str) -> bool {
+        group.hosts.iter().any(|h| h == host)
+            || group
+                .children
+                .iter()
+                .filter_map(|c| self.group(c))
+                .any(|child| self.contains(child, host))
+    }
+
+    /// Select hosts by a comma-separated list of host names, group names or
+    /// `all`, in inventory order
+    pub fn select(&self, pattern: &str) -> Result<Vec<Host>, ReployError> {
+        let mut selected: Vec<&str> = Vec::new();
+        for name in pattern
+            .split(',')
+            .map(|n| n.trim())
+            .filter(|n| !n.is_empty())
+        {
+            let name = name.strip_prefix(INVENTORY_PREFIX).unwrap_or(name);
+            if name == ALL {
+                selected.extend(self.hosts.iter().map(|h| h.name.as_str()));
+            } else if let Some(group) = self.group(name) {
+                selected.extend(
+                    self.hosts
+                        .iter()
+                        .filter(|h| self.contains(group, &h.name))
+                        .map(|h| h.name.as_str()),
+                );
+            } else if let Some(host) = self.host(name) {
+                selected.push(&host.name);
+            } else {
+                return Err(ReployError::InvalidInventory(format!(
+                    "No host or group named {}",
+                    name
+                )));
+            }
+        }
+
+        let hosts: Vec<Host> = self
+            .hosts
+            .iter()
+            .filter(|h| selected.contains(&h.name.as_str()))
+            .map(|h| self.resolve(h))
+            .collect::<Result<_, _>>()?;
+        if hosts.is_empty() {
+            return Err(ReployError::InvalidInventory(format!(
+                "No hosts match {}",
+                pattern
+            )));
+        }
+        Ok(hosts)
+    }
+
+    fn resolve(&self, host: &HostEntry) -> Result<Host, ReployError> {
+        let mut groups: Vec<(usize, &Group)> = Vec::new();
+        for group in self.groups.iter().filter(|g| self.contains(g, &host.name)) {
+            groups.push((self.depth(&group.name, &mut Vec::new())?, group));
+        }
+        // Stable, so groups at the same depth keep their inventory order
+        groups.sort_by_key(|(depth, _)| *depth);
+
+        let mut variables = HashMap::new();
+        let layers = std::iter::once(&self.vars)
+            .chain(groups.iter().map(|(_, g)| &g.vars))
+            .chain(std::iter::once(&host.vars));
+        for vars in layers {
+            variables.extend(vars.iter().cloned());
+        }
+        Ok(Host {
+            name: host.name.clone(),
+            target: host.target.clone(),
+            variables,
+        })
+    }
+}
+
+fn parse_host(name: &str, value: &Value) -> Result<HostEntry, ReployError> {
+    let context = format!("hosts.{}", name);
+    let (target, vars) = match value {
+        Value::String(target) => (target.clone(), Vec::new()),
+        Value::Table(table) => {
+            let target = match table.get("target") {
+                Some(Value::String(target)) => target.clone(),
+                Some(_) => {
+                    return Err(ReployError::InvalidInventory(format!(
+                        "{}.target must be a string",
+                        context
+                    )));
+                }
+                None => name.to_string(),
+            };
+            let vars = match table.get("vars") {
+                Some(vars) => variables(&format!("{}.vars", context), vars)?,
+                None => Vec::new(),
+            };
+            check_keys(&context, table, &["target", "vars"])?;
+            (target, vars)
+        }
+        _ => {
+            return Err(ReployError::InvalidInventory(format!(
+                "{} must be a target string or a table",
+                context
+            )));
+        }
+    };
+    target.parse::<Target>().map_err(|e| match e {
+        ReployError::InvalidRecipe(s) => {
+            ReployError::InvalidInventory(format!("{}: {}", context, s))
+        }
+        _ => e,
+    })?;
+    Ok(HostEntry {
+        name: name.to_string(),
+        target,
+        vars,
+    })
+}
+
+fn parse_group(name: &str, value: &Value) -> Result<Group, ReployError> {
+    let context = format!("groups.{}", name);
+    let table = as_table(&context, value)?;
+    check_keys(&context, table, &["hosts", "children", "vars"])?;
+    Ok(Group {
+        name: name.to_string(),
+        hosts: names(&format!("{}.hosts", context), table.get("hosts"))?,
+        children: names(&format!("{}.children", context), table.get("children"))?,
+        vars: match table.get("vars") {
+            Some(vars) => variables(&format!("{}.vars", context), vars)?,
+            None => Vec::new(),
+        },
+    })
+}
+
+fn as_table<'a>(context: &str, value: &'a Value) -> Result<&'a Table, ReployError> {
+    value
+        .as_table()
+        .ok_or_else(|| ReployError::InvalidInventory(format!("{} must be a table", context)))
+}
+
+fn check_keys(context: &str, table: &Table, allowed: &[&str]) -> Result<(), ReployError> {
+    match table.keys().find(|k| !allowed.contains(&k.as_str())) {
+        Some(key) => Err(ReployError::InvalidInventory(format!(
+            "Unknown key {}.{}",
+            context, key
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn names(context: &str, value: Option<&Value>) -> Result<Vec<String>, ReployError> {
+    let Some(value) = value else {
+        return Ok(Vec::new());
+    };
+    let invalid = || ReployError::InvalidInventory(format!("{} must be a list of names", context));
+    value
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|v| v.as_str().map(|s| s.to_string()).ok_or_else(invalid))
+        .collect()
+}
+
+/// Read a table of variables; arrays become comma-separated lists so that
+/// they can be iterated with `Each`
+fn variables(context: &str, value: &Value) -> Result<Vec<(String, String)>, ReployError> {
+    as_table(context, value)?
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| scalar(item).ok_or(item))
+                    .collect::<Result<Vec<String>, _>>()
+                    .map(|items| items.join(","))
+                    .ok(),
+                _ => scalar(value),
+            };
+            value.map(|v| (key.clone(), v)).ok_or_else(|| {
+                ReployError::InvalidInventory(format!(
+                    "{}.{} must be a string, number, boolean or list of those",
+                    context, key
+                ))
+            })
+        })
+        .collect()
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        Value::Datetime(d) => Some(d.to_string()),
+        _ => None,
+    }
+}
+
+/// The inventory selection named by the recipe's `Target @pattern`
+/// statements, if any
+pub fn selection(recipe: &Recipe) -> Result<Option<String>, ReployError> {
+    let mut selection: Option<String> = None;
+    for statement in &recipe.task {
+        if let Statement::Simple { token, arguments } = statement
+            && token.token_type == Type::TARGET
+            && let Some(pattern) = arguments[0].literal.strip_prefix(INVENTORY_PREFIX)
+        {
+            match &selection {
+                Some(previous) if previous != pattern => {
+                    return Err(ReployError::InvalidRecipe(format!(
+                        "Line {}: A recipe can only target one inventory selection, found @{} and @{}",
+                        token.line_num, previous, pattern
+                    )));
+                }
+                _ => selection = Some(pattern.to_string()),
+            }
+        }
+    }
+    Ok(selection)
+}
+
+/// A copy of `recipe` that runs against `host`, with the host's variables
+/// overriding the recipe's own. Its `Target @pattern` statements point at the
+/// host; a recipe without any, run with `--hosts`, has its first `Target`
+/// replaced, or one added when it has none.
+pub fn bind(recipe: &Recipe, host: &Host) -> Recipe {
+    let mut recipe = recipe.clone();
+    let mut targets = recipe
+        .task
+        .iter_mut()
+        .filter_map(|statement| match statement {
+            Statement::Simple { token, arguments } if token.token_type == Type::TARGET => {
+                Some(&mut arguments[0].literal)
+            }
+            _ => None,
+        });
+    let mut bound = false;
+    let mut first = None;
+    for target in targets.by_ref() {
+        if target.starts_with(INVENTORY_PREFIX) {
+            *target = host.target.clone();
+            bound = true;
+        } else if first.is_none() {
+            first = Some(target);
+        }
+    }
+    if !bound && let Some(target) = first {
+        *target = host.target.clone();
+        bound = true;
+    }
+    if !bound {
+        let token = |token_type, literal: &str| Token {
+            token_type,
+            line_num: 0,
+            literal: literal.to_string(),
+        };
+        recipe.task.insert(
+            0,
+            Statement::Simple {
+                token: token(Type::TARGET, "Target"),
+                arguments: vec![token(Type::STRING, &host.target)],
+            },
+        );
+    }
+    recipe.variables.extend(host.variables.clone());
+    recipe
+}
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use ssh2::{ErrorCode, Session};
+
+use super::executor::LIBSSH2_ERROR_EAGAIN;
+
+/// Granularity at which the keepalive thread notices it has been stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Sends SSH keepalive messages from a background thread so that idle
+/// sessions survive long `Sleep` and `Wait` statements behind firewalls.
+/// The thread stops when this handle is dropped.
+pub struct Keepalive {
+    stop: Arc<AtomicBool>,
+    lost: Arc<AtomicBool>,
+}
+
+impl Keepalive {
+    pub fn start(session: &Session, interval: Duration) -> Keepalive {
+        let stop = Arc::new(AtomicBool::new(false));
+        let lost = Arc::new(AtomicBool::new(false));
+        let interval_secs = interval.as_secs().clamp(1, u32::MAX as u64) as u32;
+        session.set_keepalive(true, interval_secs);
+
+        let session = session.clone();
+        let thread_stop = Arc::clone(&stop);
+        let thread_lost = Arc::clone(&lost);
+        thread::spawn(move || {
+            let mut wait = interval;
+            loop {
+                let mut waited = Duration::ZERO;
+                while waited < wait {
+                    if thread_stop.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                    waited += POLL_INTERVAL;
+                }
+                if thread_stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                // libssh2 only sends when the session has been idle, and
+                // tells us how long until the next one is due
+                match session.keepalive_send() {
+                    Ok(secs) => wait = Duration::from_secs(secs.max(1) as u64),
+                    // The session is busy streaming a command in non-blocking mode
+                    Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => {
+                        wait = POLL_INTERVAL
+                    }
+                    Err(_) => {
+                        thread_lost.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                }
+            }
+        });
+
+        Keepalive { stop, lost }
+    }
+
+    /// Whether a keepalive failed because the connection went away
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
@@ -1,20 +1,34 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 use token::Token;
 
+mod auth;
 pub mod error;
 pub mod evaluator;
 pub mod executor;
+pub mod inventory;
+mod keepalive;
 pub mod lexer;
+mod output;
 pub mod parser;
+pub mod rollout;
+mod shell;
+mod ssh_config;
+mod sudo;
+mod target;
+mod terminal;
 mod token;
-mod util;
+mod tunnel;
+pub mod util;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Recipe {
     pub task: Vec<Statement>,
     pub variables: HashMap<String, String>,
     pub labels: HashMap<String, Vec<Statement>>,
+    /// Interpreter every `Run` uses unless a `Shell` statement overrides it
+    pub shell: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -37,27 +51,48 @@ pub enum Statement {
     },
     While {
         condition: Token,
+        max: Option<Token>,
+        timeout: Option<Token>,
         body: Vec<Statement>,
     },
     When {
         condition: Token,
         branches: Vec<(Token, Vec<Statement>)>,
     },
+    On {
+        target: Token,
+        via: Option<Token>,
+        body: Vec<Statement>,
+    },
+    Become {
+        user: Option<Token>,
+        body: Vec<Statement>,
+    },
 }
 
+/// Target of an `On` block that runs its body on the local machine
+const LOCAL_TARGET: &str = "local";
+
+/// Exit code and output of the last command, exactly as it was written.
+/// Output beyond the capture limit, or saved with `SaveStdout`, is in the
+/// accompanying file instead.
 #[derive(Debug)]
 pub struct Stdio {
     pub exit_code: i32,
-    pub stdout: String,
-    pub stderr: String,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub stdout_file: Option<PathBuf>,
+    pub stderr_file: Option<PathBuf>,
 }
 
 impl Default for Stdio {
     fn default() -> Self {
         Self {
             exit_code: 0,
-            stdout: String::new(),
-            stderr: String::new(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            stdout_file: None,
+            stderr_file: None,
         }
     }
 }
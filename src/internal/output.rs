@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::error::ReployError;
+
+const READ_BUF_SIZE: usize = 32 * 1024;
+
+/// Output kept in memory, and the file the rest of it went to if any
+pub type Captured = (Vec<u8>, Option<PathBuf>);
+
+/// Collects one stream of command output, keeping at most `limit` bytes in
+/// memory and writing the rest to a file
+pub struct Capture {
+    name: &'static str,
+    data: Vec<u8>,
+    limit: Option<usize>,
+    file: Option<(PathBuf, BufWriter<File>)>,
+}
+
+impl Capture {
+    /// Capture the stream called `name`, spilling what exceeds `limit` to a
+    /// temporary file
+    pub fn new(name: &'static str, limit: Option<usize>) -> Capture {
+        Capture {
+            name,
+            data: Vec::new(),
+            limit,
+            file: None,
+        }
+    }
+
+    /// Write the whole stream to `path` without keeping any of it in memory
+    pub fn to_file(name: &'static str, path: &Path) -> io::Result<Capture> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Capture {
+            name,
+            data: Vec::new(),
+            limit: Some(0),
+            file: Some((path.to_path_buf(), file)),
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let room = match self.limit {
+            Some(limit) => limit.saturating_sub(self.data.len()).min(data.len()),
+            None => data.len(),
+        };
+        self.data.extend_from_slice(&data[..room]);
+        let rest = &data[room..];
+        if rest.is_empty() {
+            return Ok(());
+        }
+        if self.file.is_none() {
+            let path = spill_path(self.name);
+            let file = BufWriter::new(File::create(&path)?);
+            self.file = Some((path, file));
+        }
+        self.file.as_mut().unwrap().1.write_all(rest)
+    }
+
+    pub fn finish(self) -> io::Result<Captured> {
+        match self.file {
+            Some((path, mut file)) => {
+                file.flush()?;
+                Ok((self.data, Some(path)))
+            }
+            None => Ok((self.data, None)),
+        }
+    }
+}
+
+/// A fresh temporary file for output that did not fit into memory
+fn spill_path(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "reploy-{}-{}.{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed),
+        name
+    ))
+}
+
+/// Echoes command output to the terminal line by line as it arrives,
+/// prefixed with the target it came from
+pub struct Echo {
+    prefix: String,
+    stderr: bool,
+    pending: Vec<u8>,
+}
+
+impl Echo {
+    pub fn new(prefix: &str, stderr: bool) -> Echo {
+        Echo {
+            prefix: prefix.to_string(),
+            stderr,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Print every complete line in `data`, holding back a trailing partial line
+    pub fn write(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        while let Some(pos) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            self.print(&line[..pos]);
+        }
+    }
+
+    /// Print whatever is left once the command has finished
+    pub fn finish(&mut self) {
+        if !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            self.print(&line);
+        }
+    }
+
+    fn print(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        if self.stderr {
+            eprintln!("{} | {}", self.prefix, line);
+        } else {
+            println!("{} | {}", self.prefix, line);
+        }
+    }
+}
+
+/// Read `reader` to the end into `sink`, echoing it as it arrives when `echo`
+/// is set. Every chunk passes through `filter` first, which is handed an empty
+/// chunk once the reader is exhausted so that it can flush anything it held back.
+pub fn capture<R, F>(
+    mut reader: R,
+    mut sink: Capture,
+    mut echo: Option<Echo>,
+    mut filter: F,
+) -> Result<Captured, ReployError>
+where
+    R: Read,
+    F: FnMut(&[u8]) -> Result<Vec<u8>, ReployError>,
+{
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ReployError::Io(e)),
+        };
+        let chunk = filter(&buf[..n])?;
+        sink.write(&chunk)?;
+        if let Some(echo) = echo.as_mut() {
+            echo.write(&chunk);
+        }
+        if n == 0 {
+            break;
+        }
+    }
+    if let Some(echo) = echo.as_mut() {
+        echo.finish();
+    }
+    Ok(sink.finish()?)
+}
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 
 use super::error::ReployError;
+use super::inventory::INVENTORY_PREFIX;
 use super::lexer::Lexer;
+use super::target::Target;
 use super::token::{Token, Type};
-use super::{Recipe, Statement};
+use super::{LOCAL_TARGET, Recipe, Statement, util};
 
 pub struct Parser {
     lexer: Lexer,
@@ -19,6 +21,7 @@ impl Parser {
             task: Vec::new(),
             variables: HashMap::new(),
             labels: HashMap::new(),
+            shell: None,
         };
         loop {
             let token = self.lexer.next_token();
@@ -31,8 +34,30 @@ impl Parser {
                             token.line_num
                         )));
                     }
+                    if next_token.literal.starts_with(INVENTORY_PREFIX) {
+                        if next_token.literal.len() == 1 {
+                            return Err(ReployError::InvalidRecipe(format!(
+                                "Line {}: Missing inventory host or group after '@'",
+                                token.line_num
+                            )));
+                        }
+                    } else {
+                        validate_target(&next_token)?;
+                    }
                     let mut arguments: Vec<Token> = Vec::new();
                     arguments.push(next_token);
+                    if self.lexer.peek_token().token_type == Type::VIA {
+                        let via = self.lexer.next_token();
+                        let jump = self.lexer.next_token();
+                        if jump.token_type == Type::EOF {
+                            return Err(ReployError::InvalidRecipe(format!(
+                                "Line {}: Missing jump host after VIA",
+                                via.line_num
+                            )));
+                        }
+                        validate_target(&jump)?;
+                        arguments.push(jump);
+                    }
                     recipe.task.push(Statement::Simple { token, arguments });
                 }
                 Type::SET => {
@@ -46,6 +71,16 @@ impl Parser {
                     }
                     recipe.variables.insert(k.literal, v.literal);
                 }
+                Type::SHELL => {
+                    let shell = self.lexer.next_token();
+                    if shell.token_type == Type::EOF {
+                        return Err(ReployError::InvalidRecipe(format!(
+                            "Line {}: Missing interpreter after SHELL",
+                            token.line_num
+                        )));
+                    }
+                    recipe.shell = Some(shell.literal);
+                }
                 Type::TASK => {
                     recipe.task.append(&mut self.parse_statement()?);
                 }
@@ -82,21 +117,45 @@ impl Parser {
                 Type::WHILE => {
                     statements.push(self.parse_while()?);
                 }
-                Type::SET => {
+                Type::ON => {
+                    statements.push(self.parse_on()?);
+                }
+                Type::BECOME => {
+                    statements.push(self.parse_become()?);
+                }
+                Type::INTERACT => {
+                    // An optional command to run instead of the login shell
+                    let mut arguments: Vec<Token> = Vec::new();
+                    if self.lexer.peek_token().token_type == Type::STRING {
+                        arguments.push(self.lexer.next_token());
+                    }
+                    statements.push(Statement::Simple { token, arguments });
+                }
+                Type::SET | Type::ENV => {
                     let k = self.lexer.next_token();
                     let v = self.lexer.next_token();
                     if k.token_type == Type::EOF || v.token_type == Type::EOF {
                         return Err(ReployError::InvalidRecipe(format!(
-                            "Line {}: Incomplete SET statement",
-                            token.line_num
+                            "Line {}: Incomplete {} statement",
+                            token.line_num,
+                            token.literal.to_uppercase()
                         )));
                     }
+                    if token.token_type == Type::ENV {
+                        validate_env_name(&k)?;
+                    }
                     statements.push(Statement::Simple {
                         token,
                         arguments: vec![k, v],
                     });
                 }
-                Type::RUN | Type::PRINT | Type::CALL | Type::SLEEP => {
+                Type::RUN
+                | Type::PRINT
+                | Type::CALL
+                | Type::SLEEP
+                | Type::CD
+                | Type::SHELL
+                | Type::FAIL => {
                     let mut arguments: Vec<Token> = Vec::new();
                     let mut len = 1;
                     while len > 0 {
@@ -110,6 +169,12 @@ impl Parser {
                         arguments.push(arg);
                         len -= 1;
                     }
+                    if token.token_type == Type::SLEEP {
+                        validate_duration(&arguments[0])?;
+                    }
+                    if token.token_type == Type::RUN {
+                        self.parse_run_options(&mut arguments)?;
+                    }
                     statements.push(Statement::Simple { token, arguments });
                 }
                 Type::SND | Type::RCV | Type::ASK | Type::PWD => {
@@ -142,6 +207,9 @@ impl Parser {
                         arguments.push(arg);
                         len -= 1;
                     }
+                    if token.token_type == Type::WAIT {
+                        validate_duration(&arguments[2])?;
+                    }
                     statements.push(Statement::Simple { token, arguments });
                 }
                 Type::WHEN => {
@@ -349,6 +417,161 @@ impl Parser {
         })
     }
 
+    /// Collect the options trailing a RUN command into its arguments, each
+    /// keyword followed by its value if it takes one
+    fn parse_run_options(&mut self, arguments: &mut Vec<Token>) -> Result<(), ReployError> {
+        let mut has_input = false;
+        loop {
+            match self.lexer.peek_token().token_type {
+                Type::QUIET | Type::PTY => arguments.push(self.lexer.next_token()),
+                Type::TIMEOUT => {
+                    let option = self.lexer.next_token();
+                    let value = self.lexer.next_token();
+                    if value.token_type == Type::EOF {
+                        return Err(ReployError::InvalidRecipe(format!(
+                            "Line {}: Missing value after {} in RUN statement",
+                            option.line_num, option.literal
+                        )));
+                    }
+                    validate_duration(&value)?;
+                    arguments.push(option);
+                    arguments.push(value);
+                }
+                Type::LIMIT | Type::SAVESTDOUT => {
+                    let option = self.lexer.next_token();
+                    let value = self.lexer.next_token();
+                    if value.token_type == Type::EOF {
+                        return Err(ReployError::InvalidRecipe(format!(
+                            "Line {}: Missing value after {} in RUN statement",
+                            option.line_num, option.literal
+                        )));
+                    }
+                    if option.token_type == Type::LIMIT {
+                        validate_size(&value)?;
+                    }
+                    arguments.push(option);
+                    arguments.push(value);
+                }
+                Type::AS => {
+                    let option = self.lexer.next_token();
+                    let user = self.lexer.next_token();
+                    if user.token_type == Type::EOF {
+                        return Err(ReployError::InvalidRecipe(format!(
+                            "Line {}: Missing user after {} in RUN statement",
+                            option.line_num, option.literal
+                        )));
+                    }
+                    arguments.push(option);
+                    arguments.push(user);
+                }
+                Type::STDIN | Type::STDINFILE => {
+                    let option = self.lexer.next_token();
+                    let value = self.lexer.next_token();
+                    if value.token_type == Type::EOF {
+                        return Err(ReployError::InvalidRecipe(format!(
+                            "Line {}: Missing value after {} in RUN statement",
+                            option.line_num, option.literal
+                        )));
+                    }
+                    if has_input {
+                        return Err(ReployError::InvalidRecipe(format!(
+                            "Line {}: Only one of Stdin and StdinFile is allowed in RUN statement",
+                            option.line_num
+                        )));
+                    }
+                    has_input = true;
+                    arguments.push(option);
+                    arguments.push(value);
+                }
+                _ => break,
+            }
+        }
+        // A PTY echoes its input back and has no end of file to send
+        if has_input && arguments.iter().any(|a| a.token_type == Type::PTY) {
+            return Err(ReployError::InvalidRecipe(format!(
+                "Line {}: Stdin cannot be combined with Pty in RUN statement",
+                arguments[0].line_num
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse_on(&mut self) -> Result<Statement, ReployError> {
+        // Read target, either a host or `local`
+        let target = self.lexer.next_token();
+        if target.token_type == Type::EOF || target.token_type == Type::LBRACE {
+            return Err(ReployError::InvalidRecipe(format!(
+                "Line {}: Missing target after ON",
+                target.line_num
+            )));
+        }
+        if target.literal != LOCAL_TARGET {
+            validate_target(&target)?;
+        }
+
+        // Check for optional jump hosts
+        let mut via = None;
+        if self.lexer.peek_token().token_type == Type::VIA {
+            let via_keyword = self.lexer.next_token();
+            let jump = self.lexer.next_token();
+            if jump.token_type == Type::EOF || jump.token_type == Type::LBRACE {
+                return Err(ReployError::InvalidRecipe(format!(
+                    "Line {}: Missing jump host after VIA",
+                    via_keyword.line_num
+                )));
+            }
+            if target.literal == LOCAL_TARGET {
+                return Err(ReployError::InvalidRecipe(format!(
+                    "Line {}: ON local cannot be reached VIA a jump host",
+                    via_keyword.line_num
+                )));
+            }
+            validate_target(&jump)?;
+            via = Some(jump);
+        }
+
+        // Parse block body
+        let lbrace = self.lexer.next_token();
+        if lbrace.token_type != Type::LBRACE {
+            return Err(ReployError::InvalidRecipe(format!(
+                "Line {}: Expected '{{' after ON target",
+                lbrace.line_num
+            )));
+        }
+
+        let body = self.parse_statement()?;
+
+        Ok(Statement::On { target, via, body })
+    }
+
+    fn parse_become(&mut self) -> Result<Statement, ReployError> {
+        // Read the optional user, root unless given
+        let mut user = None;
+        if self.lexer.peek_token().token_type != Type::LBRACE {
+            let token = self.lexer.next_token();
+            if token.token_type == Type::EOF {
+                return Err(ReployError::InvalidRecipe(format!(
+                    "Line {}: Expected '{{' after BECOME",
+                    token.line_num
+                )));
+            }
+            user = Some(token);
+        }
+
+        // Parse block body
+        let lbrace = self.lexer.next_token();
+        if lbrace.token_type != Type::LBRACE {
+            return Err(ReployError::InvalidRecipe(format!(
+                "Line {}: Expected '{{' after BECOME user",
+                lbrace.line_num
+            )));
+        }
+
+        let body = self.parse_statement()?;
+
+        Ok(Statement::Become { user, body })
+    }
+
     fn parse_while(&mut self) -> Result<Statement, ReployError> {
         // Read condition
         let condition = self.lexer.next_token();
@@ -359,6 +582,30 @@ impl Parser {
             )));
         }
 
+        // Check for optional iteration and time limits
+        let mut max = None;
+        let mut timeout = None;
+        loop {
+            let next = self.lexer.peek_token();
+            if next.token_type != Type::MAX && next.token_type != Type::TIMEOUT {
+                break;
+            }
+            self.lexer.next_token();
+            let value = self.lexer.next_token();
+            if value.token_type == Type::EOF || value.token_type == Type::LBRACE {
+                return Err(ReployError::InvalidRecipe(format!(
+                    "Line {}: Missing value after {} in WHILE loop",
+                    next.line_num, next.literal
+                )));
+            }
+            if next.token_type == Type::MAX {
+                max = Some(value);
+            } else {
+                validate_duration(&value)?;
+                timeout = Some(value);
+            }
+        }
+
         // Parse loop body
         let lbrace = self.lexer.next_token();
         if lbrace.token_type != Type::LBRACE {
@@ -370,6 +617,71 @@ impl Parser {
 
         let body = self.parse_statement()?;
 
-        Ok(Statement::While { condition, body })
+        Ok(Statement::While {
+            condition,
+            max,
+            timeout,
+            body,
+        })
+    }
+}
+
+/// Reject malformed duration literals up front; values containing
+/// variables are checked once they are substituted at runtime
+fn validate_duration(token: &Token) -> Result<(), ReployError> {
+    if token.literal.contains("${") {
+        return Ok(());
+    }
+    util::parse_duration(&token.literal)
+        .map(|_| ())
+        .map_err(|_| {
+            ReployError::InvalidRecipe(format!(
+                "Line {}: Invalid duration: {}",
+                token.line_num, token.literal
+            ))
+        })
+}
+
+fn validate_size(token: &Token) -> Result<(), ReployError> {
+    if token.literal.contains("${") {
+        return Ok(());
+    }
+    util::parse_size(&token.literal).map(|_| ()).map_err(|_| {
+        ReployError::InvalidRecipe(format!(
+            "Line {}: Invalid size: {}",
+            token.line_num, token.literal
+        ))
+    })
+}
+
+/// Environment variable names must be valid shell identifiers
+fn validate_env_name(token: &Token) -> Result<(), ReployError> {
+    let mut chars = token.literal.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(ReployError::InvalidRecipe(format!(
+            "Line {}: Invalid environment variable name: {}",
+            token.line_num, token.literal
+        )));
+    }
+    Ok(())
+}
+
+/// Reject malformed targets, including each hop of a comma-separated jump chain
+fn validate_target(token: &Token) -> Result<(), ReployError> {
+    if token.literal.contains("${") {
+        return Ok(());
+    }
+    for hop in token.literal.split(',').map(|h| h.trim()) {
+        hop.parse::<Target>().map_err(|e| match e {
+            ReployError::InvalidRecipe(s) => {
+                ReployError::InvalidRecipe(format!("Line {}: {}", token.line_num, s))
+            }
+            _ => e,
+        })?;
     }
+    Ok(())
 }
@@ -0,0 +1,262 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::error::ReployError;
+use super::inventory::{self, Host};
+use super::token::{Token, Type};
+use super::{Recipe, Statement};
+
+/// How long a host took and whether its recipe succeeded
+type HostResult = (Duration, Result<(), ReployError>);
+
+/// A number of hosts, either absolute or relative to a total
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Amount {
+    Count(usize),
+    Percent(usize),
+}
+
+impl Amount {
+    fn of(&self, total: usize) -> usize {
+        match self {
+            Amount::Count(count) => *count,
+            Amount::Percent(percent) => total * percent / 100,
+        }
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ReployError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            ReployError::Runtime(format!(
+                "Invalid amount: {}, expected a number or a percentage such as 25%",
+                s
+            ))
+        };
+        match s.trim().strip_suffix('%') {
+            Some(percent) => percent
+                .parse::<usize>()
+                .ok()
+                .filter(|p| *p <= 100)
+                .map(Amount::Percent)
+                .ok_or_else(invalid),
+            None => s
+                .trim()
+                .parse::<usize>()
+                .map(Amount::Count)
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+/// Runs a recipe on many hosts: in parallel up to `forks` at a time, and in
+/// batches of `serial` hosts when rolling out, stopping once a batch has more
+/// than `max_fail` failures. A `verify` label runs on the hosts of each batch
+/// before the next one starts.
+///
+/// A host fails when its recipe, or the verification label, returns an error,
+/// such as from a `Fail` statement. `End` only stops the recipe early.
+pub struct Rollout {
+    forks: usize,
+    serial: Option<Amount>,
+    max_fail: Amount,
+    verify: Option<String>,
+}
+
+impl Rollout {
+    pub fn new(forks: usize) -> Self {
+        Rollout {
+            forks: forks.max(1),
+            serial: None,
+            max_fail: Amount::Count(0),
+            verify: None,
+        }
+    }
+
+    pub fn set_serial(&mut self, serial: Amount) {
+        self.serial = Some(serial);
+    }
+
+    pub fn set_max_fail(&mut self, max_fail: Amount) {
+        self.max_fail = max_fail;
+    }
+
+    pub fn set_verify(&mut self, label: &str) {
+        self.verify = Some(label.to_string());
+    }
+
+    /// Run `recipe` on `hosts` and print a per-host summary. `run` executes
+    /// a recipe bound to one host.
+    /// Returns whether every host succeeded.
+    pub fn run<F>(&self, recipe: &Recipe, hosts: &[Host], run: F) -> Result<bool, ReployError>
+    where
+        F: Fn(Recipe) -> Result<(), ReployError> + Sync,
+    {
+        if let Some(label) = &self.verify
+            && !recipe.labels.contains_key(label)
+        {
+            return Err(ReployError::InvalidRecipe(format!(
+                "Verification label {} not found",
+                label
+            )));
+        }
+
+        let start = Instant::now();
+        let batch_size = match self.serial {
+            Some(serial) => serial.of(hosts.len()).max(1),
+            None => hosts.len().max(1),
+        };
+        let batches: Vec<&[Host]> = hosts.chunks(batch_size).collect();
+        let mut results: Vec<Option<HostResult>> = Vec::new();
+
+        for (number, batch) in batches.iter().enumerate() {
+            if batches.len() > 1 {
+                println!(
+                    "\nBatch {}/{}: {}",
+                    number + 1,
+                    batches.len(),
+                    batch
+                        .iter()
+                        .map(|h| h.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            let mut batch_results = self.run_batch(batch, "Recipe execution", |host| {
+                run(inventory::bind(recipe, host))
+            });
+
+            // Only hosts that deployed cleanly are verified
+            if let Some(label) = &self.verify {
+                let verification = verification_recipe(recipe, label);
+                let deployed: Vec<usize> = (0..batch.len())
+                    .filter(|i| matches!(batch_results[*i], Some((_, Ok(_)))))
+                    .collect();
+                let deployed_hosts: Vec<Host> =
+                    deployed.iter().map(|i| batch[*i].clone()).collect();
+                let checks = self.run_batch(&deployed_hosts, "Verification", |host| {
+                    run(inventory::bind(&verification, host))
+                });
+                for (index, check) in deployed.into_iter().zip(checks) {
+                    if let (Some((duration, result)), Some((extra, Err(e)))) =
+                        (&mut batch_results[index], check)
+                    {
+                        *duration += extra;
+                        *result =
+                            Err(e.with_context(format!("Verification label {} failed", label)));
+                    }
+                }
+            }
+
+            let failed = batch_results
+                .iter()
+                .filter(|r| !matches!(r, Some((_, Ok(_)))))
+                .count();
+            results.extend(batch_results);
+
+            let allowed = self.max_fail.of(batch.len());
+            if self.serial.is_some() && failed > allowed && number + 1 < batches.len() {
+                eprintln!(
+                    "Stopping rollout: {} of {} hosts failed in batch {}, at most {} allowed",
+                    failed,
+                    batch.len(),
+                    number + 1,
+                    allowed
+                );
+                break;
+            }
+        }
+
+        results.resize_with(hosts.len(), || None);
+        Ok(print_summary(hosts, results, start))
+    }
+
+    /// Run `task` for every host of a batch, `forks` hosts at a time
+    fn run_batch<F>(&self, hosts: &[Host], action: &str, task: F) -> Vec<Option<HostResult>>
+    where
+        F: Fn(&Host) -> Result<(), ReployError> + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<HostResult>>> =
+            Mutex::new(hosts.iter().map(|_| None).collect());
+
+        // Each worker takes the next host and runs it on its own executor
+        thread::scope(|scope| {
+            for _ in 0..self.forks.min(hosts.len()) {
+                scope.spawn(|| {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(host) = hosts.get(index) else {
+                            break;
+                        };
+                        let host_start = Instant::now();
+                        let result = task(host);
+                        let duration = Instant::now().duration_since(host_start);
+                        match &result {
+                            Ok(_) => println!(
+                                "{} completed on {}. Duration: {:?}",
+                                action, host.name, duration
+                            ),
+                            Err(e) => eprintln!("{} failed on {}: {}", action, host.name, e),
+                        }
+                        results.lock().unwrap()[index] = Some((duration, result));
+                    }
+                });
+            }
+        });
+        results.into_inner().unwrap()
+    }
+}
+
+/// A copy of `recipe` whose task only calls `label`
+fn verification_recipe(recipe: &Recipe, label: &str) -> Recipe {
+    let token = |token_type, literal: &str| Token {
+        token_type,
+        line_num: 0,
+        literal: literal.to_string(),
+    };
+    let mut recipe = recipe.clone();
+    recipe.task = vec![Statement::Simple {
+        token: token(Type::CALL, "Call"),
+        arguments: vec![token(Type::STRING, label)],
+    }];
+    recipe
+}
+
+fn print_summary(hosts: &[Host], results: Vec<Option<HostResult>>, start: Instant) -> bool {
+    let ok = results
+        .iter()
+        .filter(|r| matches!(r, Some((_, Ok(_)))))
+        .count();
+    let skipped = results.iter().filter(|r| r.is_none()).count();
+    let failed = hosts.len() - ok - skipped;
+    let width = hosts.iter().map(|h| h.name.len()).max().unwrap_or(0);
+    println!(
+        "\nSummary: {} hosts, {} ok, {} failed, {} skipped. Duration: {:?}",
+        hosts.len(),
+        ok,
+        failed,
+        skipped,
+        Instant::now().duration_since(start)
+    );
+    for (host, result) in hosts.iter().zip(results) {
+        match result {
+            Some((duration, Ok(_))) => {
+                println!("  {:<width$}  ok       {:?}", host.name, duration)
+            }
+            Some((duration, Err(e))) => println!(
+                "  {:<width$}  failed   {:?}  {}",
+                host.name,
+                duration,
+                e.to_string().lines().next().unwrap_or_default()
+            ),
+            None => println!("  {:<width$}  skipped", host.name),
+        }
+    }
+    ok == hosts.len()
+}
@@ -0,0 +1,50 @@
+use super::error::ReployError;
+
+/// Split an interpreter setting such as `bash -euo pipefail` or `python3 -c`
+/// into the arguments a command is appended to, adding the `-c` that makes
+/// it take the command as its argument when missing
+pub fn interpreter(spec: &str) -> Result<Vec<String>, ReployError> {
+    let mut args: Vec<String> = spec.split_whitespace().map(String::from).collect();
+    if args.is_empty() {
+        return Err(ReployError::Runtime(format!("Invalid shell: {:?}", spec)));
+    }
+    if args.last().is_some_and(|arg| arg != "-c") {
+        args.push("-c".to_string());
+    }
+    Ok(args)
+}
+
+/// A shell command line running `command` with `interpreter`
+pub fn invoke(interpreter: &[String], command: &str) -> String {
+    let mut line: Vec<String> = interpreter.iter().map(|arg| quote(arg)).collect();
+    line.push(quote(command));
+    line.join(" ")
+}
+
+/// Quote `s` as a single POSIX shell word
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Quote a path, leaving a leading `~` for the shell to expand
+pub fn quote_path(path: &str) -> String {
+    match path.strip_prefix('~') {
+        Some("") => "\"$HOME\"".to_string(),
+        Some(rest) if rest.starts_with('/') => format!("\"$HOME\"{}", quote(rest)),
+        _ => quote(path),
+    }
+}
+
+/// Prefix `command` with changing to `cwd` and exporting `env`, for shells
+/// whose working directory and environment cannot be set from outside
+pub fn script(command: &str, cwd: Option<&str>, env: &[(String, String)]) -> String {
+    let mut script = String::new();
+    if let Some(cwd) = cwd {
+        script.push_str(&format!("cd {} || exit 1\n", quote_path(cwd)));
+    }
+    for (name, value) in env {
+        script.push_str(&format!("export {}={}\n", name, quote(value)));
+    }
+    script.push_str(command);
+    script
+}
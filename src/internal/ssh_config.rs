@@ -0,0 +1,310 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::error::ReployError;
+use super::util;
+
+/// Nesting limit for `Include` directives, matching OpenSSH
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Options resolved for a single host from the SSH client configuration
+#[derive(Clone, Debug, Default)]
+pub struct HostConfig {
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<PathBuf>,
+    pub proxy_jump: Option<String>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    patterns: Vec<String>,
+    keyword: String,
+    value: String,
+}
+
+/// A parsed `~/.ssh/config`, supporting `Host` blocks and `Include`.
+/// `Match` blocks are skipped.
+#[derive(Debug, Default)]
+pub struct SshConfig {
+    entries: Vec<Entry>,
+}
+
+impl SshConfig {
+    /// Load the configuration at `path`; a missing file yields an empty configuration
+    pub fn load(path: &Path) -> Result<SshConfig, ReployError> {
+        let mut config = SshConfig::default();
+        if path.exists() {
+            let base_dir = path.parent().unwrap_or(Path::new("."));
+            config.parse_file(path, base_dir, &[], 0)?;
+        }
+        Ok(config)
+    }
+
+    fn parse_file(
+        &mut self,
+        path: &Path,
+        base_dir: &Path,
+        patterns: &[String],
+        depth: usize,
+    ) -> Result<(), ReployError> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(ReployError::Runtime(format!(
+                "Too many nested includes in SSH config: {}",
+                path.display()
+            )));
+        }
+        let content = fs::read_to_string(path).map_err(|e| {
+            ReployError::Io(e)
+                .with_context(format!("Failed to read SSH config: {}", path.display()))
+        })?;
+
+        // Lines before the first Host block inherit the patterns of the
+        // including block, or apply to every host at the top level
+        let mut patterns = patterns.to_vec();
+        let mut in_match = false;
+        for line in content.lines() {
+            let Some((keyword, value)) = split_line(line) else {
+                continue;
+            };
+            match keyword.as_str() {
+                "host" => {
+                    patterns = value.split_whitespace().map(|s| s.to_string()).collect();
+                    in_match = false;
+                }
+                "match" => in_match = true,
+                _ if in_match => {}
+                "include" => {
+                    for pattern in value.split_whitespace() {
+                        for file in expand_include(pattern, base_dir) {
+                            self.parse_file(&file, base_dir, &patterns, depth + 1)?;
+                        }
+                    }
+                }
+                _ => self.entries.push(Entry {
+                    patterns: patterns.clone(),
+                    keyword,
+                    value,
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve the options for `alias` the way `ssh` does: the first value
+    /// obtained for each option wins, while identity files accumulate
+    pub fn resolve(&self, alias: &str) -> HostConfig {
+        let mut config = HostConfig::default();
+        for entry in &self.entries {
+            if !host_matches(&entry.patterns, alias) {
+                continue;
+            }
+            match entry.keyword.as_str() {
+                "hostname" if config.host_name.is_none() => {
+                    config.host_name = Some(entry.value.replace("%h", alias));
+                }
+                "user" if config.user.is_none() => {
+                    config.user = Some(entry.value.clone());
+                }
+                "port" if config.port.is_none() => {
+                    config.port = entry.value.parse::<u16>().ok();
+                }
+                "proxyjump" if config.proxy_jump.is_none() => {
+                    config.proxy_jump = Some(entry.value.clone());
+                }
+                "identityfile" => {
+                    let host = config.host_name.as_deref().unwrap_or(alias);
+                    config.identity_files.push(expand_path(
+                        &entry.value,
+                        host,
+                        config.user.as_deref(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        config
+    }
+}
+
+/// Split a config line into a lowercased keyword and its value,
+/// accepting both `Keyword value` and `Keyword=value`
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let pos = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let keyword = line[..pos].to_lowercase();
+    let value = line[pos..].trim_start();
+    let value = value.strip_prefix('=').unwrap_or(value).trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    Some((keyword, value.to_string()))
+}
+
+/// An empty pattern list means the entry precedes any Host block
+fn host_matches(patterns: &[String], host: &str) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+    let mut matched = false;
+    for pattern in patterns {
+        if let Some(negated) = pattern.strip_prefix('!') {
+            if wildcard_match(negated, host) {
+                return false;
+            }
+        } else if wildcard_match(pattern, host) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Match `text` against a pattern where `*` matches any run of characters
+/// and `?` matches exactly one
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            ti = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// Expand `~` and the `%d`, `%h`, `%r` and `%%` tokens in a path
+fn expand_path(value: &str, host: &str, user: Option<&str>) -> PathBuf {
+    let home = util::home_dir().unwrap_or_default();
+    let value = match value.strip_prefix("~/") {
+        Some(rest) => format!("{}/{}", home.display(), rest),
+        None => value.to_string(),
+    };
+    let mut expanded = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('d') => expanded.push_str(&home.display().to_string()),
+            Some('h') => expanded.push_str(host),
+            Some('r') => expanded.push_str(user.unwrap_or_default()),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    PathBuf::from(expanded)
+}
+
+/// Resolve an `Include` argument relative to the configuration directory,
+/// expanding wildcards in the file name
+fn expand_include(pattern: &str, base_dir: &Path) -> Vec<PathBuf> {
+    let path = match pattern.strip_prefix("~/") {
+        Some(rest) => util::home_dir().unwrap_or_default().join(rest),
+        None => base_dir.join(pattern),
+    };
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !file_name.contains('*') && !file_name.contains('?') {
+        return if path.is_file() {
+            vec![path]
+        } else {
+            Vec::new()
+        };
+    }
+
+    let dir = path.parent().unwrap_or(base_dir);
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.is_file())
+                .filter(|p| {
+                    p.file_name()
+                        .map(|n| wildcard_match(&file_name, &n.to_string_lossy()))
+                        .unwrap_or(false)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(list: &[&str]) -> Vec<String> {
+        list.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_match("web", "web"));
+        assert!(!wildcard_match("web", "web1"));
+        assert!(wildcard_match("web?", "web1"));
+        assert!(!wildcard_match("web?", "web"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*.example.com", "db.example.com"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(wildcard_match("*a*b", "xaxxab"));
+        assert!(wildcard_match("10.0.*.?", "10.0.12.3"));
+        assert!(!wildcard_match("a*b", "acbc"));
+    }
+
+    #[test]
+    fn matches_host_patterns() {
+        assert!(host_matches(&[], "anything"));
+        assert!(host_matches(&patterns(&["db", "web*"]), "web2"));
+        assert!(!host_matches(&patterns(&["db", "web*"]), "cache"));
+        // A matching negation excludes the host whatever else matches
+        assert!(!host_matches(&patterns(&["*", "!bastion"]), "bastion"));
+        assert!(host_matches(&patterns(&["*", "!bastion"]), "web"));
+        // A negation alone never selects a host
+        assert!(!host_matches(&patterns(&["!bastion"]), "web"));
+    }
+
+    #[test]
+    fn splits_config_lines() {
+        assert_eq!(
+            split_line("  HostName web.example.com "),
+            Some(("hostname".to_string(), "web.example.com".to_string()))
+        );
+        assert_eq!(
+            split_line("Port=2222"),
+            Some(("port".to_string(), "2222".to_string()))
+        );
+        assert_eq!(
+            split_line("IdentityFile \"~/.ssh/my key\""),
+            Some(("identityfile".to_string(), "~/.ssh/my key".to_string()))
+        );
+        assert_eq!(split_line("# comment"), None);
+        assert_eq!(split_line(""), None);
+    }
+}
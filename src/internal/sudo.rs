@@ -0,0 +1,208 @@
+use super::error::ReployError;
+use super::shell::quote;
+
+/// Password prompt handed to sudo, distinctive enough not to occur in real output
+const PROMPT: &[u8] = b"[reploy] sudo password: ";
+
+/// Printed by the wrapped command once sudo has let it start, so that input
+/// meant for the command is not fed to sudo's password prompt
+const READY: &[u8] = b"[reploy] sudo ready";
+
+/// Wrap `command` to run as `user` through sudo. Without a PTY sudo reads the
+/// password from stdin; with one it reads from the terminal, which keeps the
+/// answer from being echoed back into the output.
+pub fn wrap(command: &str, user: &str, pty: bool) -> String {
+    let ready = std::str::from_utf8(READY).unwrap();
+    format!(
+        "sudo {}-p {} -u {} -- sh -c {}",
+        if pty { "" } else { "-S " },
+        quote(std::str::from_utf8(PROMPT).unwrap()),
+        quote(user),
+        quote(&format!("printf %s {} >&2\n{}", quote(ready), command))
+    )
+}
+
+/// Watches a stream of command output for sudo's password prompt, strips it
+/// and tells the caller when to answer it and when the command has started
+pub struct PromptWatcher {
+    has_password: bool,
+    pending: Vec<u8>,
+    prompts: usize,
+    ready: bool,
+}
+
+impl PromptWatcher {
+    pub fn new(has_password: bool) -> Self {
+        PromptWatcher {
+            has_password,
+            pending: Vec::new(),
+            prompts: 0,
+            ready: false,
+        }
+    }
+
+    /// Whether sudo has let the command start
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Feed the next chunk of output. Returns the output with any prompt
+    /// removed, and whether the password should be written to stdin now.
+    pub fn feed(&mut self, data: &[u8]) -> Result<(Vec<u8>, bool), ReployError> {
+        // Everything after the command started is its own output
+        if self.ready {
+            return Ok((data.to_vec(), false));
+        }
+        let mut buf = std::mem::take(&mut self.pending);
+        buf.extend_from_slice(data);
+
+        let mut answer = false;
+        loop {
+            match (find(&buf, PROMPT), find(&buf, READY)) {
+                (Some(prompt), ready) if ready.is_none_or(|ready| prompt < ready) => {
+                    buf.drain(prompt..prompt + PROMPT.len());
+                    self.prompts += 1;
+                    if !self.has_password {
+                        return Err(ReployError::BecomeFailed(
+                            "sudo asked for a password, pass one with --become-password"
+                                .to_string(),
+                        ));
+                    }
+                    if self.prompts > 1 {
+                        return Err(ReployError::BecomeFailed(
+                            "sudo rejected the become password".to_string(),
+                        ));
+                    }
+                    answer = true;
+                }
+                (_, Some(ready)) => {
+                    buf.drain(ready..ready + READY.len());
+                    self.ready = true;
+                    return Ok((buf, answer));
+                }
+                _ => break,
+            }
+        }
+
+        // Hold back a tail that could be the start of a marker split across chunks
+        let keep = [PROMPT, READY]
+            .iter()
+            .filter_map(|marker| {
+                (1..marker.len().min(buf.len() + 1))
+                    .rev()
+                    .find(|n| buf.ends_with(&marker[..*n]))
+            })
+            .max()
+            .unwrap_or(0);
+        self.pending = buf.split_off(buf.len() - keep);
+        Ok((buf, answer))
+    }
+
+    /// Whatever was held back once the stream has ended
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `chunks` in order, collecting the output and how often a password was asked for
+    fn feed_all(watcher: &mut PromptWatcher, chunks: &[&[u8]]) -> (Vec<u8>, usize) {
+        let mut output = Vec::new();
+        let mut answers = 0;
+        for chunk in chunks {
+            let (data, answer) = watcher.feed(chunk).unwrap();
+            output.extend(data);
+            answers += answer as usize;
+        }
+        output.extend(watcher.finish());
+        (output, answers)
+    }
+
+    #[test]
+    fn strips_prompt_and_ready_marker() {
+        let mut watcher = PromptWatcher::new(true);
+        let input = [PROMPT, READY, b"error: disk full\n"].concat();
+        let (output, answers) = feed_all(&mut watcher, &[&input]);
+        assert_eq!(output, b"error: disk full\n");
+        assert_eq!(answers, 1);
+        assert!(watcher.is_ready());
+    }
+
+    #[test]
+    fn finds_markers_split_across_chunks() {
+        let input = [b"warning\n".as_slice(), PROMPT, READY, b"done\n"].concat();
+        // Every split point, including ones inside each marker
+        for split in 0..input.len() {
+            let mut watcher = PromptWatcher::new(true);
+            let (head, tail) = input.split_at(split);
+            let (output, answers) = feed_all(&mut watcher, &[head, tail]);
+            assert_eq!(output, b"warning\ndone\n", "split at {}", split);
+            assert_eq!(answers, 1, "split at {}", split);
+            assert!(watcher.is_ready(), "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let input = [PROMPT, READY, b"ok"].concat();
+        let chunks: Vec<&[u8]> = input.chunks(1).collect();
+        let mut watcher = PromptWatcher::new(true);
+        let (output, answers) = feed_all(&mut watcher, &chunks);
+        assert_eq!(output, b"ok");
+        assert_eq!(answers, 1);
+    }
+
+    #[test]
+    fn passes_output_through_once_ready() {
+        let mut watcher = PromptWatcher::new(false);
+        let input = [READY, PROMPT].concat();
+        let (output, answers) = feed_all(&mut watcher, &[&input]);
+        // A prompt printed by the command itself is its own output
+        assert_eq!(output, PROMPT);
+        assert_eq!(answers, 0);
+    }
+
+    #[test]
+    fn keeps_partial_marker_at_end_of_stream() {
+        let mut watcher = PromptWatcher::new(true);
+        let (output, _) = feed_all(&mut watcher, &[b"[reploy] su"]);
+        assert_eq!(output, b"[reploy] su");
+        assert!(!watcher.is_ready());
+    }
+
+    #[test]
+    fn fails_without_password() {
+        let mut watcher = PromptWatcher::new(false);
+        assert!(matches!(
+            watcher.feed(PROMPT),
+            Err(ReployError::BecomeFailed(_))
+        ));
+    }
+
+    #[test]
+    fn fails_when_password_is_rejected() {
+        let mut watcher = PromptWatcher::new(true);
+        assert!(watcher.feed(PROMPT).unwrap().1);
+        assert!(matches!(
+            watcher.feed(b"Sorry, try again.\n[reploy] sudo password: "),
+            Err(ReployError::BecomeFailed(_))
+        ));
+    }
+
+    #[test]
+    fn wraps_command_for_sudo() {
+        assert_eq!(
+            wrap("id -u", "deploy", false),
+            "sudo -S -p '[reploy] sudo password: ' -u 'deploy' -- sh -c \
+             'printf %s '\\''[reploy] sudo ready'\\'' >&2\nid -u'"
+        );
+        assert!(wrap("id -u", "root", true).starts_with("sudo -p "));
+    }
+}
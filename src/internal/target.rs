@@ -0,0 +1,201 @@
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+use super::error::ReployError;
+
+const URI_SCHEME: &str = "ssh://";
+
+/// A remote target in the form `[ssh://][user@]host[:port]`, where an IPv6
+/// host is either bracketed (`[2001:db8::1]:2222`) or bare without a port
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl FromStr for Target {
+    type Err = ReployError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            ReployError::InvalidRecipe(format!("Invalid target '{}': {}", s, reason))
+        };
+
+        let mut rest = s.trim();
+        if rest
+            .get(..URI_SCHEME.len())
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case(URI_SCHEME))
+        {
+            rest = &rest[URI_SCHEME.len()..];
+            rest = rest.strip_suffix('/').unwrap_or(rest);
+        }
+
+        let user = match rest.rfind('@') {
+            Some(pos) => {
+                let user = &rest[..pos];
+                if user.is_empty() {
+                    return Err(invalid("empty user name"));
+                }
+                rest = &rest[pos + 1..];
+                Some(user.to_string())
+            }
+            None => None,
+        };
+
+        let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+            let (host, after) = bracketed
+                .split_once(']')
+                .ok_or_else(|| invalid("missing ']' after IPv6 address"))?;
+            let port = match after {
+                "" => None,
+                _ => Some(
+                    after
+                        .strip_prefix(':')
+                        .ok_or_else(|| invalid("expected ':' after ']'"))?,
+                ),
+            };
+            (host, port)
+        } else if rest.matches(':').count() > 1 {
+            // A bare IPv6 address cannot carry a port
+            if rest.parse::<Ipv6Addr>().is_err() {
+                return Err(invalid(
+                    "IPv6 addresses with a port must be enclosed in '[]'",
+                ));
+            }
+            (rest, None)
+        } else {
+            match rest.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(invalid("empty host"));
+        }
+        if host
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '/' | '@' | '[' | ']'))
+        {
+            return Err(invalid("host contains invalid characters"));
+        }
+        let port = match port {
+            Some(port) => Some(
+                port.parse::<u16>()
+                    .ok()
+                    .filter(|p| *p != 0)
+                    .ok_or_else(|| invalid("port must be a number between 1 and 65535"))?,
+            ),
+            None => None,
+        };
+
+        Ok(Target {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        match self.port {
+            Some(port) => write!(f, "{}", host_port(&self.host, port)),
+            None => write!(f, "{}", self.host),
+        }
+    }
+}
+
+/// Format a host and port, bracketing IPv6 addresses as OpenSSH does
+pub fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(user: Option<&str>, host: &str, port: Option<u16>) -> Target {
+        Target {
+            user: user.map(str::to_string),
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parses_user_host_and_port() {
+        assert_eq!("web".parse::<Target>().unwrap(), target(None, "web", None));
+        assert_eq!(
+            "deploy@web:2222".parse::<Target>().unwrap(),
+            target(Some("deploy"), "web", Some(2222))
+        );
+        // The last '@' separates the user, which may contain one itself
+        assert_eq!(
+            "me@corp@web".parse::<Target>().unwrap(),
+            target(Some("me@corp"), "web", None)
+        );
+    }
+
+    #[test]
+    fn parses_ssh_uri() {
+        assert_eq!(
+            "ssh://root@db.example.com:22/".parse::<Target>().unwrap(),
+            target(Some("root"), "db.example.com", Some(22))
+        );
+        assert_eq!(
+            "SSH://db".parse::<Target>().unwrap(),
+            target(None, "db", None)
+        );
+    }
+
+    #[test]
+    fn parses_ipv6() {
+        assert_eq!(
+            "root@[2001:db8::1]:2222".parse::<Target>().unwrap(),
+            target(Some("root"), "2001:db8::1", Some(2222))
+        );
+        assert_eq!(
+            "[::1]".parse::<Target>().unwrap(),
+            target(None, "::1", None)
+        );
+        assert_eq!(
+            "2001:db8::1".parse::<Target>().unwrap(),
+            target(None, "2001:db8::1", None)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_targets() {
+        for s in [
+            "@web",
+            "root@",
+            "root@:22",
+            "web:0",
+            "web:65536",
+            "web:ssh",
+            "[::1",
+            "[::1]2222",
+            "2001:db8::1:2222:x",
+            "web/app",
+        ] {
+            assert!(s.parse::<Target>().is_err(), "{} should be rejected", s);
+        }
+    }
+
+    #[test]
+    fn displays_like_it_parses() {
+        for s in ["web", "deploy@web:2222", "root@[2001:db8::1]:2222"] {
+            assert_eq!(s.parse::<Target>().unwrap().to_string(), s);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::process::{Command, Stdio};
+
+/// Terminal type requested for remote PTYs when `TERM` is not set
+const DEFAULT_TERM: &str = "xterm";
+
+/// Size of a PTY when the local terminal's is unknown, as (columns, rows)
+pub const DEFAULT_SIZE: (u32, u32) = (80, 24);
+
+/// The local terminal type to request for remote PTYs
+pub fn term() -> String {
+    std::env::var("TERM").unwrap_or_else(|_| DEFAULT_TERM.to_string())
+}
+
+/// Size of the terminal on stdin as (columns, rows)
+pub fn size() -> Option<(u32, u32)> {
+    let size = stty(&["size"])?;
+    let mut parts = size.split_whitespace().map(|n| n.parse::<u32>().ok());
+    match (parts.next()??, parts.next()??) {
+        (0, _) | (_, 0) => None,
+        (rows, columns) => Some((columns, rows)),
+    }
+}
+
+/// Keeps the terminal on stdin in raw mode, so that every key goes straight
+/// to the remote side, until dropped. Reads return after at most 100ms even
+/// without input so that readers can notice when to stop.
+pub struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    /// Switch to raw mode, or `None` when stdin is not a terminal
+    pub fn enable() -> Option<RawMode> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo", "min", "0", "time", "1"])?;
+        Some(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
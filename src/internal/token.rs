@@ -25,12 +25,29 @@ pub enum Type {
     RBRACE,
     STRING,
     TARGET,
+    VIA,
+    ON,
     WAIT,
     SLEEP,
     FOR,
-    EACH, 
+    EACH,
     IN,
     WHILE,
+    MAX,
+    TIMEOUT,
+    QUIET,
+    AS,
+    BECOME,
+    CD,
+    ENV,
+    PTY,
+    INTERACT,
+    STDIN,
+    STDINFILE,
+    LIMIT,
+    SAVESTDOUT,
+    SHELL,
+    FAIL,
     EXPRESSION, // For (...) expressions
     EQEQ,       // ==
     NOTEQ,      // !=
@@ -60,12 +77,29 @@ pub fn lookup_identifier(identifier: String) -> Type {
         "Label" => Type::LABEL,
         "Print" => Type::PRINT,
         "Target" => Type::TARGET,
+        "Via" => Type::VIA,
+        "On" => Type::ON,
         "Wait" => Type::WAIT,
         "Sleep" => Type::SLEEP,
         "For" => Type::FOR,
         "Each" => Type::EACH,
         "In" => Type::IN,
         "While" => Type::WHILE,
+        "Max" => Type::MAX,
+        "Timeout" => Type::TIMEOUT,
+        "Quiet" => Type::QUIET,
+        "As" => Type::AS,
+        "Become" => Type::BECOME,
+        "Cd" => Type::CD,
+        "Env" => Type::ENV,
+        "Pty" => Type::PTY,
+        "Interact" => Type::INTERACT,
+        "Stdin" => Type::STDIN,
+        "StdinFile" => Type::STDINFILE,
+        "Limit" => Type::LIMIT,
+        "SaveStdout" => Type::SAVESTDOUT,
+        "Shell" => Type::SHELL,
+        "Fail" => Type::FAIL,
         "==" => Type::EQEQ,
         "!=" => Type::NOTEQ,
         ">" => Type::GT,
@@ -0,0 +1,103 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use ssh2::{Channel, Session};
+
+use super::error::ReployError;
+use super::target;
+
+const TUNNEL_BUF_SIZE: usize = 32 * 1024;
+
+/// How long the forwarding loop sleeps when neither side has data
+const IDLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Open a `direct-tcpip` channel from `jump` to `host:port` and expose it as a
+/// local socket, so that a new session can be handshaken through it.
+///
+/// libssh2 sessions need a real socket, so the channel is bridged to a
+/// loopback connection by a forwarding thread that lives as long as either
+/// end stays open.
+pub fn open(jump: &Session, host: &str, port: u16) -> Result<TcpStream, ReployError> {
+    let channel = jump.channel_direct_tcpip(host, port, None).map_err(|e| {
+        ReployError::Ssh(e).with_context(format!(
+            "Failed to open tunnel to {}",
+            target::host_port(host, port)
+        ))
+    })?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let local = TcpStream::connect(listener.local_addr()?)?;
+    let remote = loop {
+        let (stream, addr) = listener.accept()?;
+        // Ignore anyone else racing to connect to the ephemeral port
+        if addr == local.local_addr()? {
+            break stream;
+        }
+    };
+
+    let session = jump.clone();
+    thread::spawn(move || forward(session, channel, remote));
+    Ok(local)
+}
+
+fn forward(session: Session, mut channel: Channel, mut socket: TcpStream) {
+    // The jump session is only used by this thread from now on
+    session.set_blocking(false);
+    if socket.set_nonblocking(true).is_err() {
+        return;
+    }
+
+    let mut buf = vec![0u8; TUNNEL_BUF_SIZE];
+    loop {
+        let mut idle = true;
+
+        match socket.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                if write_all(&mut channel, &buf[..n]).is_err() {
+                    break;
+                }
+                idle = false;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => break,
+        }
+
+        match channel.read(&mut buf) {
+            Ok(0) => {
+                if channel.eof() {
+                    break;
+                }
+            }
+            Ok(n) => {
+                if write_all(&mut socket, &buf[..n]).is_err() {
+                    break;
+                }
+                idle = false;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => break,
+        }
+
+        if idle {
+            thread::sleep(IDLE_INTERVAL);
+        }
+    }
+    let _ = channel.close();
+}
+
+/// `write_all` for non-blocking writers, retrying while the peer is busy
+pub(super) fn write_all<W: Write>(writer: &mut W, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(IDLE_INTERVAL),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
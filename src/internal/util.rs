@@ -1,5 +1,7 @@
 use super::error::ReployError;
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
 
 /// Check if a file exists at the given path
 pub fn file_exists(path: &str) -> bool {
@@ -15,10 +17,150 @@ pub fn home_dir() -> Option<PathBuf> {
     dirs::home_dir()
 }
 
-pub fn ssh_key() -> PathBuf {
+pub fn known_hosts() -> PathBuf {
     home_dir()
-        .map(|d| d.join(".ssh").join("id_rsa"))
-        .unwrap_or(PathBuf::new())
+        .map(|d| d.join(".ssh").join("known_hosts"))
+        .unwrap_or_default()
+}
+
+pub fn ssh_config() -> PathBuf {
+    home_dir()
+        .map(|d| d.join(".ssh").join("config"))
+        .unwrap_or_default()
+}
+
+/// The default identity files, in the order `ssh` tries them
+pub fn ssh_keys() -> Vec<PathBuf> {
+    home_dir()
+        .map(|d| {
+            ["id_rsa", "id_ecdsa", "id_ed25519"]
+                .iter()
+                .map(|name| d.join(".ssh").join(name))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Hold while talking to the user on the terminal, so that prompts from
+/// recipes running on several hosts at once do not interleave
+pub fn prompt_lock() -> MutexGuard<'static, ()> {
+    static PROMPT: Mutex<()> = Mutex::new(());
+    PROMPT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Encode bytes as standard base64 without padding, as used by OpenSSH fingerprints
+pub fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..chunk.len() + 1 {
+            out.push(ALPHABET[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+        }
+    }
+    out
+}
+
+/// Standard base64 with `=` padding, as `base64 -d` expects
+pub fn base64_encode_padded(data: &[u8]) -> String {
+    let mut out = base64_encode(data);
+    while !out.len().is_multiple_of(4) {
+        out.push('=');
+    }
+    out
+}
+
+/// Decode standard base64, ignoring whitespace and padding
+pub fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut buf: u32 = 0;
+    let mut bits = 0;
+    for c in data
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b'=')
+    {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buf = buf << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// Parse a size literal such as `512`, `64K`, `10M` or `1G` in bytes, with
+/// binary units
+pub fn parse_size(s: &str) -> Result<usize, ReployError> {
+    let invalid = || ReployError::Runtime(format!("Invalid size: {}", s));
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let value = s[..digits].parse::<usize>().map_err(|_| invalid())?;
+    let unit = match s[digits..]
+        .trim_end_matches(['B', 'b'])
+        .to_ascii_uppercase()
+        .as_str()
+    {
+        "" => 1,
+        "K" | "KI" => 1 << 10,
+        "M" | "MI" => 1 << 20,
+        "G" | "GI" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    value.checked_mul(unit).ok_or_else(invalid)
+}
+
+/// Parse a duration literal such as `500ms`, `10s`, `5m`, `1h30m` or `1.5s`;
+/// bare numbers are seconds
+pub fn parse_duration(s: &str) -> Result<Duration, ReployError> {
+    let invalid = || ReployError::Runtime(format!("Invalid duration: {}", s));
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(seconds) = s.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).map_err(|_| invalid());
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let num_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(invalid)?;
+        let unit_len = rest[num_len..]
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len() - num_len);
+        let value = rest[..num_len].parse::<f64>().map_err(|_| invalid())?;
+        let unit_secs = match &rest[num_len..num_len + unit_len] {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            "d" => 86400.0,
+            _ => return Err(invalid()),
+        };
+        total = Duration::try_from_secs_f64(value * unit_secs)
+            .ok()
+            .and_then(|d| total.checked_add(d))
+            .ok_or_else(invalid)?;
+        rest = &rest[num_len + unit_len..];
+    }
+    Ok(total)
 }
 
 pub fn evaluate_expression(expr: &str) -> Result<String, ReployError> {
@@ -35,17 +177,65 @@ pub fn evaluate_expression(expr: &str) -> Result<String, ReployError> {
     }
 }
 
+// Binary operators grouped by precedence, lowest first
+const OPERATORS: [&[&str]; 4] = [
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+#[derive(Clone, Copy, Debug)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn parse(s: &str) -> Option<Number> {
+        let s = s.trim();
+        if let Ok(i) = s.parse::<i64>() {
+            return Some(Number::Int(i));
+        }
+        match s.parse::<f64>() {
+            Ok(f) if f.is_finite() => Some(Number::Float(f)),
+            _ => None,
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+}
+
+impl std::fmt::Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Number::Int(i) => write!(f, "{}", i),
+            Number::Float(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Find the rightmost occurrence of `op` in `expr`, returning its position and
+/// length. Operators need spaces on both sides, except `*` which cannot appear
+/// in a number and is also recognised without them, e.g. `(3*2)`.
+fn find_operator(expr: &str, op: &str) -> Option<(usize, usize)> {
+    if op == "*" {
+        return expr.rfind('*').map(|pos| (pos, 1));
+    }
+    expr.rfind(&format!(" {} ", op))
+        .map(|pos| (pos, op.len() + 2))
+}
+
 fn is_expression(expr: &str) -> bool {
-    expr.contains('*')
-        || expr.contains(" / ")
-        || expr.contains(" + ")
-        || expr.contains(" - ")
-        || expr.contains(" < ")
-        || expr.contains(" > ")
-        || expr.contains(" == ")
-        || expr.contains(" != ")
-        || expr.contains(" >= ")
-        || expr.contains(" <= ")
+    OPERATORS
+        .iter()
+        .flat_map(|ops| ops.iter())
+        .any(|op| find_operator(expr, op).is_some())
         || expr.starts_with("file_exists")
         || expr.starts_with("dir_exists")
 }
@@ -63,124 +253,197 @@ fn handle_expression(expr: &str) -> Result<String, ReployError> {
         return Ok(dir_exists(path).to_string());
     }
 
-    // Handle multiplication and division first (higher precedence)
-    if let Some(pos) = expr.find('*') {
-        let left = expr[..pos].trim().to_string();
-        let right = expr[pos + 1..].trim().to_string();
-        let left_num = left
-            .parse::<i32>()
-            .map_err(|_| ReployError::Runtime(format!("Invalid number: {}", left)))?;
-        let right_num = right
-            .parse::<i32>()
-            .map_err(|_| ReployError::Runtime(format!("Invalid number: {}", right)))?;
-        return Ok((left_num * right_num).to_string());
-    }
-
-    if let Some(pos) = expr.find('/') {
-        let left = expr[..pos].trim().to_string();
-        let right = expr[pos + 1..].trim().to_string();
-        let left_num = left
-            .parse::<i32>()
-            .map_err(|_| ReployError::Runtime(format!("Invalid number: {}", left)))?;
-        let right_num = right
-            .parse::<i32>()
-            .map_err(|_| ReployError::Runtime(format!("Invalid number: {}", right)))?;
-        if right_num == 0 {
-            return Err(ReployError::Runtime("Division by zero".to_string()));
-        }
-        return Ok((left_num / right_num).to_string());
-    }
-
-    // Handle addition and subtraction
-    if let Some(pos) = expr.find('+') {
-        let left = expr[..pos].trim().to_string();
-        let right = expr[pos + 1..].trim().to_string();
-        let left_num = left
-            .parse::<i32>()
-            .map_err(|_| ReployError::Runtime(format!("Invalid number: {}", left)))?;
-        let right_num = right
-            .parse::<i32>()
-            .map_err(|_| ReployError::Runtime(format!("Invalid number: {}", right)))?;
-        return Ok((left_num + right_num).to_string());
-    }
-
-    if let Some(pos) = expr.find('-') {
-        let left = expr[..pos].trim().to_string();
-        let right = expr[pos + 1..].trim().to_string();
-        let left_num = left
-            .parse::<i32>()
-            .map_err(|_| ReployError::Runtime(format!("Invalid number: {}", left)))?;
-        let right_num = right
-            .parse::<i32>()
-            .map_err(|_| ReployError::Runtime(format!("Invalid number: {}", right)))?;
-        return Ok((left_num - right_num).to_string());
-    }
-
-    // Handle comparison operators
-    if expr.contains("<") {
-        let parts: Vec<&str> = expr.split('<').collect();
-        if parts.len() == 2 {
-            let left = parts[0].trim().to_string();
-            let right = parts[1].trim().to_string();
-            let left_num = left.parse::<i32>().unwrap_or(0);
-            let right_num = right.parse::<i32>().unwrap_or(0);
-            return Ok((left_num < right_num).to_string());
+    // Split on the rightmost operator of the lowest precedence level so that
+    // operators of equal precedence associate to the left
+    for ops in OPERATORS {
+        let split = ops
+            .iter()
+            .filter_map(|op| find_operator(expr, op).map(|(pos, len)| (pos, len, *op)))
+            .max_by_key(|(pos, _, _)| *pos);
+        if let Some((pos, len, op)) = split {
+            let left = handle_expression(expr[..pos].trim())?;
+            let right = handle_expression(expr[pos + len..].trim())?;
+            return apply_operator(op, &left, &right, expr);
         }
     }
 
-    if expr.contains(">") {
-        let parts: Vec<&str> = expr.split('>').collect();
-        if parts.len() == 2 {
-            let left = parts[0].trim().to_string();
-            let right = parts[1].trim().to_string();
-            let left_num = left.parse::<i32>().unwrap_or(0);
-            let right_num = right.parse::<i32>().unwrap_or(0);
-            return Ok((left_num > right_num).to_string());
-        }
+    Ok(expr.to_string())
+}
+
+fn apply_operator(op: &str, left: &str, right: &str, expr: &str) -> Result<String, ReployError> {
+    let operands = (Number::parse(left), Number::parse(right));
+
+    // Equality falls back to string comparison for non-numeric operands
+    if op == "==" || op == "!=" {
+        let equal = match operands {
+            (Some(Number::Int(l)), Some(Number::Int(r))) => l == r,
+            (Some(l), Some(r)) => l.as_f64() == r.as_f64(),
+            _ => left == right,
+        };
+        return Ok((equal == (op == "==")).to_string());
     }
 
-    if expr.contains("==") {
-        let parts: Vec<&str> = expr.split("==").collect();
-        if parts.len() == 2 {
-            let left = parts[0].trim().to_string();
-            let right = parts[1].trim().to_string();
-            return Ok((left == right).to_string());
+    let (l, r) = match operands {
+        (Some(l), Some(r)) => (l, r),
+        (None, _) => {
+            return Err(ReployError::Runtime(format!(
+                "Invalid number: {} in expression: {}",
+                left, expr
+            )));
+        }
+        (_, None) => {
+            return Err(ReployError::Runtime(format!(
+                "Invalid number: {} in expression: {}",
+                right, expr
+            )));
         }
+    };
+
+    if matches!(op, "<" | ">" | "<=" | ">=") {
+        let ordering = match (l, r) {
+            (Number::Int(l), Number::Int(r)) => l.partial_cmp(&r),
+            _ => l.as_f64().partial_cmp(&r.as_f64()),
+        };
+        let result = match (op, ordering) {
+            ("<", Some(o)) => o.is_lt(),
+            (">", Some(o)) => o.is_gt(),
+            ("<=", Some(o)) => o.is_le(),
+            (">=", Some(o)) => o.is_ge(),
+            _ => false,
+        };
+        return Ok(result.to_string());
+    }
+
+    if (op == "/" || op == "%") && r.as_f64() == 0.0 {
+        return Err(ReployError::Runtime(format!("Division by zero: {}", expr)));
     }
 
-    if expr.contains("!=") {
-        let parts: Vec<&str> = expr.split("!=").collect();
-        if parts.len() == 2 {
-            let left = parts[0].trim().to_string();
-            let right = parts[1].trim().to_string();
-            return Ok((left != right).to_string());
+    let result = match (l, r) {
+        (Number::Int(l), Number::Int(r)) => {
+            let value = match op {
+                "+" => l.checked_add(r),
+                "-" => l.checked_sub(r),
+                "*" => l.checked_mul(r),
+                "/" => l.checked_div(r),
+                _ => l.checked_rem(r),
+            };
+            Number::Int(value.ok_or_else(|| {
+                ReployError::Runtime(format!("Integer overflow in expression: {}", expr))
+            })?)
+        }
+        _ => {
+            let (l, r) = (l.as_f64(), r.as_f64());
+            let value = match op {
+                "+" => l + r,
+                "-" => l - r,
+                "*" => l * r,
+                "/" => l / r,
+                _ => l % r,
+            };
+            if !value.is_finite() {
+                return Err(ReployError::Runtime(format!(
+                    "Floating point overflow in expression: {}",
+                    expr
+                )));
+            }
+            Number::Float(value)
         }
+    };
+    Ok(result.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> String {
+        evaluate_expression(expr).unwrap()
+    }
+
+    #[test]
+    fn evaluates_arithmetic() {
+        assert_eq!(eval("(1 + 2)"), "3");
+        assert_eq!(eval("(7 % 3)"), "1");
+        assert_eq!(eval("(7 / 2)"), "3");
+        assert_eq!(eval("(7.0 / 2)"), "3.5");
+        assert_eq!(eval("(0.5 * 3)"), "1.5");
+        assert_eq!(eval("(-3 + 1)"), "-2");
+        assert_eq!(eval("(5000000000 * 2)"), "10000000000");
+    }
+
+    #[test]
+    fn respects_precedence_and_associativity() {
+        assert_eq!(eval("(1 + 2 * 3)"), "7");
+        assert_eq!(eval("(10 - 4 - 3)"), "3");
+        assert_eq!(eval("(8 / 4 / 2)"), "1");
+        assert_eq!(eval("(2 * 3 == 6)"), "true");
+        assert_eq!(eval("(1 + 1 < 3)"), "true");
+    }
+
+    #[test]
+    fn multiplies_without_spaces() {
+        assert_eq!(eval("(3*2)"), "6");
+        assert_eq!(eval("(6 / 3*2)"), "4");
+        assert_eq!(eval("(3*2 + 1)"), "7");
+        assert!(evaluate_expression("(x*2)").is_err());
     }
 
-    if expr.contains(">=") {
-        let parts: Vec<&str> = expr.split(">=").collect();
-        if parts.len() == 2 {
-            let left = parts[0].trim().to_string();
-            let right = parts[1].trim().to_string();
-            let left_num = left.parse::<i32>().unwrap_or(0);
-            let right_num = right.parse::<i32>().unwrap_or(0);
-            return Ok((left_num >= right_num).to_string());
+    #[test]
+    fn compares_numbers_and_strings() {
+        assert_eq!(eval("(10 > 9)"), "true");
+        assert_eq!(eval("(2 <= 2)"), "true");
+        assert_eq!(eval("(1.0 == 1)"), "true");
+        assert_eq!(eval("(yes == yes)"), "true");
+        assert_eq!(eval("(yes != no)"), "true");
+        assert!(evaluate_expression("(a < 3)").is_err());
+    }
+
+    #[test]
+    fn reports_arithmetic_errors() {
+        assert!(evaluate_expression("(1 / 0)").is_err());
+        assert!(evaluate_expression("(1 % 0)").is_err());
+        assert!(evaluate_expression("(9223372036854775807 + 1)").is_err());
+        assert!(evaluate_expression("(one + 1)").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::from_secs(172800));
+        assert_eq!(parse_duration(" 0s ").unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for s in ["", "-1", "10x", "s", "1h30", "1..5s", "NaN", "inf"] {
+            assert!(parse_duration(s).is_err(), "{} should be rejected", s);
         }
     }
 
-    if expr.contains("<=") {
-        let parts: Vec<&str> = expr.split(">=").collect();
-        if parts.len() == 2 {
-            let left = parts[0].trim().to_string();
-            let right = parts[1].trim().to_string();
-            let left_num = left.parse::<i32>().unwrap_or(0);
-            let right_num = right.parse::<i32>().unwrap_or(0);
-            return Ok((left_num <= right_num).to_string());
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("10m").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("1GiB").unwrap(), 1 << 30);
+        assert_eq!(parse_size("2KB").unwrap(), 2048);
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for s in ["", "K", "1.5M", "-1", "10T", "18446744073709551615K"] {
+            assert!(parse_size(s).is_err(), "{} should be rejected", s);
         }
     }
 
-    return Err(ReployError::Runtime(format!(
-        "Invalid expression: {}",
-        expr
-    )));
+    #[test]
+    fn keeps_literals() {
+        assert_eq!(eval("plain"), "plain");
+        assert_eq!(eval("(2024-01-01)"), "2024-01-01");
+        assert_eq!(eval("(/usr/local/bin)"), "/usr/local/bin");
+    }
 }
@@ -3,16 +3,25 @@ extern crate dialoguer;
 extern crate regex;
 extern crate ssh2;
 
-use clap::{Arg, ArgAction, Command};
-use std::time::Instant;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use internal::Recipe;
+use internal::error::ReployError;
 use internal::evaluator::Evaluator;
-use internal::executor::{Executor, LocalExecutor, SshExecutor};
+use internal::executor::{Executor, HostKeyPolicy, LocalExecutor, SshExecutor};
+use internal::inventory::{self, Host, Inventory};
 use internal::lexer::Lexer;
 use internal::parser::Parser;
+use internal::rollout::{Amount, Rollout};
+use internal::util;
 
 mod internal;
 
+/// Number of hosts a recipe runs on concurrently unless `--forks` says otherwise
+const DEFAULT_FORKS: usize = 5;
+
 fn main() {
     let cmd = Command::new("reploy")
         .version("0.3.1")
@@ -24,6 +33,20 @@ fn main() {
                 .action(ArgAction::SetTrue)
                 .help("Enable verbose output"),
         )
+        .arg(
+            Arg::new("max-call-depth")
+                .long("max-call-depth")
+                .value_name("DEPTH")
+                .value_parser(clap::value_parser!(usize))
+                .help("Maximum nesting depth of Call statements"),
+        )
+        .arg(
+            Arg::new("command-timeout")
+                .long("command-timeout")
+                .value_name("DURATION")
+                .value_parser(parse_duration)
+                .help("Kill commands that run longer than this unless they set their own Timeout"),
+        )
         .subcommand(
             Command::new("ssh")
                 .about("Run the specified recipe over SSH")
@@ -32,7 +55,8 @@ fn main() {
                         .short('i')
                         .long("identity")
                         .value_name("KEY FILE")
-                        .help("The identity file to use for key-based authentication"),
+                        .action(ArgAction::Append)
+                        .help("An identity file to use for key-based authentication (repeatable)"),
                 )
                 .arg(
                     Arg::new("password")
@@ -41,6 +65,57 @@ fn main() {
                         .action(ArgAction::SetTrue)
                         .help("Prompt for password authentication"),
                 )
+                .arg(
+                    Arg::new("config")
+                        .short('F')
+                        .long("config")
+                        .value_name("CONFIG FILE")
+                        .help("The SSH client configuration file to read (default: ~/.ssh/config)"),
+                )
+                .arg(
+                    Arg::new("jump")
+                        .short('J')
+                        .long("jump")
+                        .value_name("HOSTS")
+                        .help("Connect through these comma-separated jump hosts"),
+                )
+                .arg(
+                    Arg::new("connect-timeout")
+                        .long("connect-timeout")
+                        .value_name("DURATION")
+                        .value_parser(parse_duration)
+                        .help("Give up connecting to a host after this long (default: 30s)"),
+                )
+                .arg(
+                    Arg::new("handshake-timeout")
+                        .long("handshake-timeout")
+                        .value_name("DURATION")
+                        .value_parser(parse_duration)
+                        .help("Give up on the SSH handshake after this long (default: 30s)"),
+                )
+                .arg(
+                    Arg::new("keepalive")
+                        .long("keepalive")
+                        .value_name("DURATION")
+                        .value_parser(parse_duration)
+                        .help("Interval between SSH keepalives, 0 to disable (default: 30s)"),
+                )
+                .arg(
+                    Arg::new("host-key-checking")
+                        .long("host-key-checking")
+                        .value_name("POLICY")
+                        .value_parser(["strict", "accept-new", "off"])
+                        .default_value("strict")
+                        .help("How to verify server host keys against known_hosts"),
+                )
+                .arg(
+                    Arg::new("known-hosts")
+                        .long("known-hosts")
+                        .value_name("FILE")
+                        .help("The known_hosts file to verify host keys against"),
+                )
+                .args(inventory_args())
+                .arg(become_arg())
                 .arg(
                     Arg::new("recipe")
                         .required(true)
@@ -50,6 +125,8 @@ fn main() {
         .subcommand(
             Command::new("run")
                 .about("Run the specified recipe locally")
+                .args(inventory_args())
+                .arg(become_arg())
                 .arg(
                     Arg::new("recipe")
                         .required(true)
@@ -78,30 +155,229 @@ fn main() {
         }
     };
 
-    let executor: Box<dyn Executor> = if is_local {
-        Box::new(LocalExecutor::new())
-    } else {
-        let mut executor = SshExecutor::new();
-        if sub_matches.contains_id("identity") {
-            executor.set_identity(sub_matches.get_one::<String>("identity").unwrap());
+    let hosts = match select_hosts(&parsed_recipe, sub_matches) {
+        Ok(hosts) => hosts,
+        Err(e) => {
+            eprintln!("Failed to select hosts: {}", e);
+            std::process::exit(1);
         }
-        if sub_matches.get_flag("password") {
-            let password = dialoguer::Password::new()
+    };
+
+    let password = if !is_local && sub_matches.get_flag("password") {
+        Some(
+            dialoguer::Password::new()
                 .with_prompt("SSH password")
                 .interact()
-                .unwrap_or_default();
-            executor.set_password(&password);
+                .unwrap_or_default(),
+        )
+    } else {
+        None
+    };
+    let become_password = if sub_matches.get_flag("become-password") {
+        Some(
+            dialoguer::Password::new()
+                .with_prompt("Become password")
+                .interact()
+                .unwrap_or_default(),
+        )
+    } else {
+        None
+    };
+
+    let Some(hosts) = hosts else {
+        let executor = build_executor(
+            is_local,
+            sub_matches,
+            password.as_deref(),
+            become_password.as_deref(),
+        );
+        match run_recipe(
+            parsed_recipe,
+            &matches,
+            executor,
+            become_password.as_deref(),
+        ) {
+            Ok(_) => println!(
+                "Recipe execution completed. Duration: {:?}",
+                Instant::now().duration_since(start)
+            ),
+            Err(e) => eprintln!("Recipe execution failed: {}", e),
         }
-        Box::new(executor)
+        return;
     };
 
-    let mut evaluator = Evaluator::new(parsed_recipe, matches.get_flag("verbose"), executor);
+    let mut rollout = Rollout::new(
+        sub_matches
+            .get_one::<u32>("forks")
+            .map(|forks| *forks as usize)
+            .unwrap_or(DEFAULT_FORKS),
+    );
+    if let Some(serial) = sub_matches.get_one::<Amount>("serial") {
+        rollout.set_serial(*serial);
+    }
+    if let Some(max_fail) = sub_matches.get_one::<Amount>("max-fail") {
+        rollout.set_max_fail(*max_fail);
+    }
+    if let Some(label) = sub_matches.get_one::<String>("verify") {
+        rollout.set_verify(label);
+    }
 
-    match evaluator.run() {
-        Ok(_) => println!(
-            "Recipe execution completed. Duration: {:?}",
-            Instant::now().duration_since(start)
-        ),
-        Err(e) => eprintln!("Recipe execution failed: {}", e),
+    let result = rollout.run(&parsed_recipe, &hosts, |recipe| {
+        let executor = build_executor(
+            is_local,
+            sub_matches,
+            password.as_deref(),
+            become_password.as_deref(),
+        );
+        run_recipe(recipe, &matches, executor, become_password.as_deref()).map(|_| ())
+    });
+    match result {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("Recipe execution failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn inventory_args() -> [Arg; 6] {
+    [
+        Arg::new("inventory")
+            .long("inventory")
+            .value_name("FILE")
+            .help("A TOML inventory of hosts, groups and their variables"),
+        Arg::new("hosts")
+            .long("hosts")
+            .value_name("PATTERN")
+            .requires("inventory")
+            .help("Run once per host in these comma-separated inventory hosts or groups"),
+        Arg::new("forks")
+            .short('f')
+            .long("forks")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .help("Run the recipe on at most this many hosts at once (default: 5)"),
+        Arg::new("serial")
+            .long("serial")
+            .value_name("N|N%")
+            .value_parser(parse_amount)
+            .help("Roll out in batches of this many hosts or this share of all hosts"),
+        Arg::new("max-fail")
+            .long("max-fail")
+            .value_name("N|N%")
+            .value_parser(parse_amount)
+            .requires("serial")
+            .help("Stop the rollout when more hosts than this fail in a batch (default: 0)"),
+        Arg::new("verify")
+            .long("verify")
+            .value_name("LABEL")
+            .help("Run this label on each host of a batch before starting the next"),
+    ]
+}
+
+fn become_arg() -> Arg {
+    Arg::new("become-password")
+        .short('K')
+        .long("become-password")
+        .action(ArgAction::SetTrue)
+        .help("Prompt for the sudo password of Run ... As and Become")
+}
+
+/// The inventory hosts to run the recipe against, chosen by `--hosts` or the
+/// recipe's `Target @pattern`, or `None` for a single plain run
+fn select_hosts(recipe: &Recipe, matches: &ArgMatches) -> Result<Option<Vec<Host>>, ReployError> {
+    let pattern = match matches.get_one::<String>("hosts") {
+        Some(pattern) => Some(pattern.clone()),
+        None => inventory::selection(recipe)?,
+    };
+    let path = matches.get_one::<String>("inventory");
+    match (pattern, path) {
+        (Some(pattern), Some(path)) => {
+            Ok(Some(Inventory::load(Path::new(path))?.select(&pattern)?))
+        }
+        (Some(pattern), None) => Err(ReployError::InvalidRecipe(format!(
+            "Target @{} needs an inventory, pass one with --inventory",
+            pattern
+        ))),
+        (None, _) => Ok(None),
+    }
+}
+
+fn build_executor(
+    is_local: bool,
+    matches: &ArgMatches,
+    password: Option<&str>,
+    become_password: Option<&str>,
+) -> Box<dyn Executor> {
+    if is_local {
+        let mut executor = LocalExecutor::new();
+        if let Some(password) = become_password {
+            executor.set_become_password(password);
+        }
+        return Box::new(executor);
+    }
+    let mut executor = SshExecutor::new();
+    if let Some(identities) = matches.get_many::<String>("identity") {
+        for identity in identities {
+            executor.add_identity(identity);
+        }
     }
+    if let Some(password) = password {
+        executor.set_password(password);
+    }
+    if let Some(policy) = matches.get_one::<String>("host-key-checking") {
+        executor.set_host_key_policy(policy.parse::<HostKeyPolicy>().unwrap());
+    }
+    if let Some(path) = matches.get_one::<String>("config") {
+        executor.set_ssh_config(path);
+    }
+    if let Some(jump) = matches.get_one::<String>("jump") {
+        executor.set_jump(jump);
+    }
+    if let Some(timeout) = matches.get_one::<Duration>("connect-timeout") {
+        executor.set_connect_timeout(*timeout);
+    }
+    if let Some(timeout) = matches.get_one::<Duration>("handshake-timeout") {
+        executor.set_handshake_timeout(*timeout);
+    }
+    if let Some(interval) = matches.get_one::<Duration>("keepalive") {
+        executor.set_keepalive_interval(*interval);
+    }
+    if let Some(path) = matches.get_one::<String>("known-hosts") {
+        executor.set_known_hosts(path);
+    }
+    if let Some(password) = become_password {
+        executor.set_become_password(password);
+    }
+    Box::new(executor)
+}
+
+/// Run a recipe to completion, returning whether it stopped at an `End`
+fn run_recipe(
+    recipe: Recipe,
+    matches: &ArgMatches,
+    executor: Box<dyn Executor>,
+    become_password: Option<&str>,
+) -> Result<bool, ReployError> {
+    let mut evaluator = Evaluator::new(recipe, matches.get_flag("verbose"), executor);
+    if let Some(password) = become_password {
+        evaluator.set_become_password(password);
+    }
+    if let Some(depth) = matches.get_one::<usize>("max-call-depth") {
+        evaluator.set_max_call_depth(*depth);
+    }
+    if let Some(timeout) = matches.get_one::<Duration>("command-timeout") {
+        evaluator.set_command_timeout(*timeout);
+    }
+    evaluator.run()?;
+    Ok(evaluator.is_end())
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    util::parse_duration(s).map_err(|e| e.to_string())
+}
+
+fn parse_amount(s: &str) -> Result<Amount, String> {
+    s.parse::<Amount>().map_err(|e| e.to_string())
 }